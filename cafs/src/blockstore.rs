@@ -1,102 +1,446 @@
 use std::collections::HashMap;
 use std::ffi::OsString;
-use std::io::{Read, Seek, BufReader};
-use std::fs::File;
+use std::io::{self, Read, BufReader, ErrorKind};
+use std::fs::{self, File, read_dir};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use sha2::{Sha512, Digest};
-use std::io::SeekFrom;
-use readchain::{Take,Chain};
+use readchain::Chain;
+use fdpool::{POOL, RangeReader};
 
-pub struct BlockStore {
-    pub blocks: HashMap<String, Block>,
+/// what to do when two blocks with different content have the same hash
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CollisionPolicy {
+    Panic,
+    Error,  // insert returns an error
+    Log,    // print a warning and keep the existing block
+}
+
+/// content addressed storage of blocks.
+/// a Block only describes where its content can be read from, so stores that keep
+/// their own copy of the content read it from the shards on put.
+pub trait BlockStore : Send + Sync {
+    fn get(&self, hash: &String) -> io::Result<Block>;
+    fn put(&mut self, hash: String, block: Block) -> io::Result<()>;
+    fn contains(&self, hash: &String) -> bool;
+    fn list(&self) -> Vec<String>;
+    fn size(&self, hash: &String) -> Option<usize>;
 }
 
+#[derive(Clone)]
 pub struct Block {
     pub shards: Vec<BlockShard>,
     pub size: usize,
 }
 
+#[derive(Clone)]
 pub struct BlockShard {
     pub file:    OsString,
     pub offset:  usize,
     pub size:    usize,
+    pub data:    Option<Arc<Vec<u8>>>, // content that was never written to a file, read from here instead
+}
+
+impl Block {
+    /// a block whose content is only in memory, like an encrypted block before it is stored
+    pub fn memory(data: Vec<u8>) -> Block {
+        let size = data.len();
+        Block{
+            shards: vec![BlockShard{
+                file:   OsString::new(),
+                offset: 0,
+                size:   size,
+                data:   Some(Arc::new(data)),
+            }],
+            size: size,
+        }
+    }
+}
+
+fn not_found(hash: &String) -> io::Error {
+    io::Error::new(ErrorKind::NotFound, format!("block {} not found", hash))
 }
 
+fn is_hash(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|c| c.is_ascii_hexdigit())
+}
 
-pub fn new() -> BlockStore {
-    BlockStore{
+
+/// keeps only the location of blocks, the content stays in the files it was chunked from
+pub struct MemoryStore {
+    pub blocks: HashMap<String, Block>,
+}
+
+pub fn memory() -> MemoryStore {
+    MemoryStore{
         blocks: HashMap::new(),
     }
 }
 
+impl BlockStore for MemoryStore {
+    fn get(&self, hash: &String) -> io::Result<Block> {
+        self.blocks.get(hash).cloned().ok_or_else(|| not_found(hash))
+    }
+    fn put(&mut self, hash: String, block: Block) -> io::Result<()> {
+        self.blocks.insert(hash, block);
+        Ok(())
+    }
+    fn contains(&self, hash: &String) -> bool {
+        self.blocks.contains_key(hash)
+    }
+    fn list(&self) -> Vec<String> {
+        self.blocks.keys().cloned().collect()
+    }
+    fn size(&self, hash: &String) -> Option<usize> {
+        self.blocks.get(hash).map(|b| b.size)
+    }
+}
+
+
+/// a directory of loose blocks, each stored in a file named by its hash
+pub struct LooseStore {
+    dir: PathBuf,
+}
+
+pub fn loose<P: AsRef<Path>>(dir: P) -> io::Result<LooseStore> {
+    fs::create_dir_all(&dir)?;
+    Ok(LooseStore{
+        dir: dir.as_ref().to_path_buf(),
+    })
+}
 
-impl BlockStore {
-    pub fn get<'a>(&'a self, hash: &String) -> Option<&'a Block> {
-        self.blocks.get(hash)
+impl BlockStore for LooseStore {
+    fn get(&self, hash: &String) -> io::Result<Block> {
+        let path = self.dir.join(hash);
+        let size = match fs::metadata(&path) {
+            Ok(m) => m.len() as usize,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Err(not_found(hash)),
+            Err(e) => return Err(e),
+        };
+        Ok(Block{
+            shards: vec![BlockShard{
+                file:   path.into_os_string(),
+                offset: 0,
+                size:   size,
+                data:   None,
+            }],
+            size: size,
+        })
     }
-    pub fn insert(&mut self, hash: String, block: Block) {
+    fn put(&mut self, hash: String, block: Block) -> io::Result<()> {
+        let path = self.dir.join(&hash);
+        if path.exists() {
+            return Ok(());
+        }
+        let tmp = self.dir.join(format!("{}.tmp", hash));
+        io::copy(&mut block.chain(), &mut File::create(&tmp)?)?;
+        fs::rename(&tmp, &path)
+    }
+    fn contains(&self, hash: &String) -> bool {
+        self.dir.join(hash).exists()
+    }
+    fn list(&self) -> Vec<String> {
+        let entries = match read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        entries.filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().into_string().ok())
+            .filter(|name| is_hash(name))
+            .collect()
+    }
+    fn size(&self, hash: &String) -> Option<usize> {
+        fs::metadata(self.dir.join(hash)).ok().map(|m| m.len() as usize)
+    }
+}
+
 
-        //sanity check on hash
+/// reads go to a fast store first and fall back to a slow one, copying what was found
+/// into the fast store. writes only go to the fast store.
+pub struct Layered {
+    fast: Mutex<Box<BlockStore>>,
+    slow: Box<BlockStore>,
+}
+
+pub fn layered(fast: Box<BlockStore>, slow: Box<BlockStore>) -> Layered {
+    Layered{
+        fast: Mutex::new(fast),
+        slow: slow,
+    }
+}
+
+impl BlockStore for Layered {
+    fn get(&self, hash: &String) -> io::Result<Block> {
         {
-            let mut br = BufReader::new(block.chain());
-            let hs = Sha512::digest_reader(&mut br).unwrap();
-            let hs = format!("{:x}", hs);
-            if hs != hash {
+            let fast = self.fast.lock().unwrap();
+            if fast.contains(hash) {
+                return fast.get(hash);
+            }
+        }
+        let block = self.slow.get(hash)?;
+        let mut fast = self.fast.lock().unwrap();
+        fast.put(hash.clone(), block)?;
+        fast.get(hash)
+    }
+    fn put(&mut self, hash: String, block: Block) -> io::Result<()> {
+        self.fast.lock().unwrap().put(hash, block)
+    }
+    fn contains(&self, hash: &String) -> bool {
+        self.fast.lock().unwrap().contains(hash) || self.slow.contains(hash)
+    }
+    fn list(&self) -> Vec<String> {
+        let mut l = self.fast.lock().unwrap().list();
+        l.extend(self.slow.list());
+        l.sort();
+        l.dedup();
+        l
+    }
+    fn size(&self, hash: &String) -> Option<usize> {
+        let s = self.fast.lock().unwrap().size(hash);
+        s.or_else(|| self.slow.size(hash))
+    }
+}
+
+
+/// fill buf as far as possible, only returning less than buf.len() at EOF
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut didread = 0;
+    while didread < buf.len() {
+        match r.read(&mut buf[didread..]) {
+            Ok(0) => break,
+            Ok(rs) => didread += rs,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(didread)
+}
 
-                let mut br = BufReader::new(block.chain());
-                let mut content = Vec::new();
-                let rs = br.read_to_end(&mut content).unwrap();
+/// streaming comparison of two readers. true if both produce exactly the same bytes
+pub fn same_content<A: Read, B: Read>(mut a: A, mut b: B) -> io::Result<bool> {
+    let mut ba = [0; 4096];
+    let mut bb = [0; 4096];
+    loop {
+        let ra = read_full(&mut a, &mut ba)?;
+        let rb = read_full(&mut b, &mut bb)?;
+        if ra != rb || ba[..ra] != bb[..rb] {
+            return Ok(false);
+        }
+        if ra == 0 {
+            return Ok(true);
+        }
+    }
+}
 
-                if rs != block.size {
-                    panic!(format!("BUG: block should be {} bytes but did read {}", block.size, content.len()));
-                }
 
+/// verify a block against its hash and add it to the store
+pub fn insert(store: &mut BlockStore, hash: String, block: Block, policy: CollisionPolicy) -> io::Result<()> {
 
-                let hs2 = Sha512::digest(&content);
-                let hs2 = format!("{:x}", hs2);
-                if hs2 != hs2 {
-                    panic!("BUG: in chainreader: hash from read_to_end doesn't match digest_reader");
-                }
+    //sanity check on hash
+    {
+        let mut br = BufReader::new(block.chain());
+        let hs = Sha512::digest_reader(&mut br)?;
+        let hs = format!("{:x}", hs);
+        if hs != hash {
 
-                panic!("BUG: inserted block hash id doesn't match its content");
+            let mut br = BufReader::new(block.chain());
+            let mut content = Vec::new();
+            let rs = br.read_to_end(&mut content)?;
+
+            if rs != block.size {
+                panic!(format!("BUG: block should be {} bytes but did read {}", block.size, content.len()));
             }
-        }
 
-        //collision check
-        if self.blocks.contains_key(&hash) {
-
-            let mut ra = BufReader::new(block.chain());
-            let mut rb = BufReader::new(self.blocks[&hash].chain());
-            loop {
-                let mut a: [u8;1024] = [0; 1024];
-                let mut b: [u8;1024] = [0; 1024];
-                ra.read(&mut a).unwrap();
-                let rs = rb.read(&mut b).unwrap();
-
-                if a[..] != b[..] {
-                    println!("!!!!!! HASH COLLISION !!!!!!!!!!!!!!!!!!!!!");
-                    println!("this is extremly unlikely,save your block store for research.");
-                    println!("{:?}", hash);
-                    panic!("hash collision");
-                }
-
-                if rs < 1 {
-                    break;
-                }
+
+            let hs2 = Sha512::digest(&content);
+            let hs2 = format!("{:x}", hs2);
+            if hs2 != hs {
+                panic!("BUG: in chainreader: hash from read_to_end doesn't match digest_reader");
             }
+
+            panic!("BUG: inserted block hash id doesn't match its content");
         }
+    }
 
-        self.blocks.insert(hash, block);
+    insert_verified(store, hash, block, policy)
+}
+
+/// add a block whose hash has already been checked against its content
+fn insert_verified(store: &mut BlockStore, hash: String, block: Block, policy: CollisionPolicy) -> io::Result<()> {
+
+    //collision check
+    if store.contains(&hash) {
+        let existing = store.get(&hash)?;
+        let collision = existing.size != block.size ||
+            !same_content(block.chain(), existing.chain())?;
+
+        if !collision {
+            return Ok(());
+        }
+
+        match policy {
+            CollisionPolicy::Panic => {
+                println!("!!!!!! HASH COLLISION !!!!!!!!!!!!!!!!!!!!!");
+                println!("this is extremly unlikely,save your block store for research.");
+                println!("{:?}", hash);
+                panic!("hash collision");
+            },
+            CollisionPolicy::Error => {
+                return Err(io::Error::new(ErrorKind::InvalidData,
+                                          format!("hash collision on block {}", hash)));
+            },
+            CollisionPolicy::Log => {
+                warn!("hash collision on block {}, keeping the existing block", hash);
+                return Ok(());
+            },
+        }
     }
 
+    store.put(hash, block)
 }
 
 impl Block {
-    pub fn chain<'a>(&'a self) -> Chain<'a, Take<File>> {
-        let it = self.shards.iter().map(|shard| {
-            let mut f = File::open(&shard.file).unwrap();
-            f.seek(SeekFrom::Current(shard.offset as i64)).unwrap();
-            Take::limit(f, shard.size)
+    pub fn chain<'a>(&'a self) -> Chain<'a, RangeReader<'static>> {
+        let it = self.shards.iter().map(|shard| match shard.data {
+            Some(ref data) => RangeReader::memory(data.clone(), shard.offset as u64, shard.size as u64),
+            None => RangeReader::new(&POOL, shard.file.clone(), shard.offset as u64, shard.size as u64),
         });
         Chain::new(Box::new(it))
     }
+
+    /// like chain, but owning the shards
+    pub fn into_chain(self) -> Chain<'static, RangeReader<'static>> {
+        let it = self.shards.into_iter().map(|shard| match shard.data {
+            Some(data) => RangeReader::memory(data, shard.offset as u64, shard.size as u64),
+            None => RangeReader::new(&POOL, shard.file, shard.offset as u64, shard.size as u64),
+        });
+        Chain::new(Box::new(it))
+    }
+}
+
+
+#[cfg(test)]
+pub fn test_block(shards: Vec<(&str, usize, usize)>) -> Block {
+    let shards : Vec<BlockShard> = shards.into_iter().map(|(f,o,l)| BlockShard{
+        file:   OsString::from(f),
+        offset: o,
+        size:   l,
+        data:   None,
+    }).collect();
+    let size = shards.iter().fold(0, |acc, s| acc + s.size);
+    Block{
+        shards: shards,
+        size: size,
+    }
+}
+
+/// yields one byte per read call
+#[cfg(test)]
+struct Dribble<R: Read>(R);
+
+#[cfg(test)]
+impl<R: Read> Read for Dribble<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let l = if buf.len() > 0 { 1 } else { 0 };
+        self.0.read(&mut buf[..l])
+    }
+}
+
+#[test]
+fn same_content_short_reads() {
+    let a : &[u8] = b"yaya cool stuff";
+    let b : &[u8] = b"yaya cool stuff";
+    assert!(same_content(Dribble(a), b).unwrap());
+    assert!(same_content(a, Dribble(b)).unwrap());
+
+    let c : &[u8] = b"yaya cool stuf";
+    assert!(!same_content(Dribble(a), c).unwrap());
+    assert!(!same_content(c, Dribble(a)).unwrap());
+
+    let d : &[u8] = b"yaya cool stufF";
+    assert!(!same_content(a, d).unwrap());
+}
+
+#[test]
+fn insert_same_block_twice() {
+    let mut bs = memory();
+    let hash = format!("{:x}", Sha512::digest(b"yayacool"));
+    insert(&mut bs, hash.clone(), test_block(vec![("test/readchain/a", 0, 4), ("test/readchain/b", 0, 4)]),
+           CollisionPolicy::Error).unwrap();
+
+    // same content, different shard layout
+    insert(&mut bs, hash.clone(), test_block(vec![("test/readchain/a", 0, 2), ("test/readchain/a", 2, 2),
+                                                  ("test/readchain/b", 0, 4)]),
+           CollisionPolicy::Error).unwrap();
+    assert_eq!(bs.blocks.len(), 1);
+}
+
+#[test]
+fn fake_collision_error() {
+    let mut bs = memory();
+    let p = CollisionPolicy::Error;
+    let fake = String::from("fake");
+    insert_verified(&mut bs, fake.clone(), test_block(vec![("test/readchain/a", 0, 4)]), p).unwrap();
+
+    // same length, different content
+    assert!(insert_verified(&mut bs, fake.clone(), test_block(vec![("test/readchain/b", 0, 4)]), p).is_err());
+    // different length
+    assert!(insert_verified(&mut bs, fake.clone(), test_block(vec![("test/readchain/a", 0, 2)]), p).is_err());
+    // shorter shards which happen to be a prefix
+    assert!(insert_verified(&mut bs, fake.clone(), test_block(vec![("test/readchain/a", 0, 4),
+                                                                   ("test/readchain/b", 0, 1)]), p).is_err());
+}
+
+#[test]
+fn fake_collision_log() {
+    let mut bs = memory();
+    let p = CollisionPolicy::Log;
+    insert_verified(&mut bs, String::from("fake"), test_block(vec![("test/readchain/a", 0, 4)]), p).unwrap();
+    insert_verified(&mut bs, String::from("fake"), test_block(vec![("test/readchain/b", 0, 4)]), p).unwrap();
+
+    let mut content = String::new();
+    bs.get(&String::from("fake")).unwrap().chain().read_to_string(&mut content).unwrap();
+    assert_eq!(content, "yaya");
+}
+
+#[test]
+#[should_panic(expected = "hash collision")]
+fn fake_collision_panic() {
+    let mut bs = memory();
+    let p = CollisionPolicy::Panic;
+    insert_verified(&mut bs, String::from("fake"), test_block(vec![("test/readchain/a", 0, 4)]), p).unwrap();
+    insert_verified(&mut bs, String::from("fake"), test_block(vec![("test/readchain/b", 0, 4)]), p).unwrap();
+}
+
+#[cfg(test)]
+fn read_block(store: &BlockStore, hash: &str) -> String {
+    let mut content = String::new();
+    store.get(&String::from(hash)).unwrap().chain().read_to_string(&mut content).unwrap();
+    content
+}
+
+#[test]
+fn loose_and_layered() {
+    let tmp = ::tarball::TempDir::new("layered");
+
+    let mut slow = loose(tmp.join("slow")).unwrap();
+    slow.put(String::from("aa"), test_block(vec![("test/readchain/a", 0, 4)])).unwrap();
+    slow.put(String::from("bb"), test_block(vec![("test/readchain/b", 0, 4)])).unwrap();
+    assert_eq!(read_block(&slow, "bb"), "cool");
+    assert_eq!(slow.size(&String::from("bb")), Some(4));
+
+    let fast = loose(tmp.join("fast")).unwrap();
+    let mut l = layered(Box::new(fast), Box::new(slow));
+    l.put(String::from("cc"), test_block(vec![("test/readchain/b", 5, 5)])).unwrap();
+
+    assert!(!tmp.join("fast/aa").exists());
+    assert_eq!(read_block(&l, "aa"), "yaya");
+    assert!(tmp.join("fast/aa").exists());
+    assert!(l.get(&String::from("dd")).is_err());
+
+    let mut list = l.list();
+    list.sort();
+    assert_eq!(list, vec!["aa", "bb", "cc"]);
+    assert_eq!(list.iter().map(|h| l.size(h).unwrap()).sum::<usize>(), 13);
+    assert!(!tmp.join("slow/cc").exists());
 }
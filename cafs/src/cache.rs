@@ -0,0 +1,182 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, ErrorKind};
+use std::sync::{Arc, Mutex};
+use sha2::{Sha512, Digest};
+
+use blockstore::BlockStore;
+
+
+pub const DEFAULT_BUDGET: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub hits:      u64,
+    pub misses:    u64,
+    pub readahead: u64, // blocks loaded ahead of being read
+    pub evictions: u64,
+}
+
+struct Entry {
+    data: Arc<Vec<u8>>,
+    used: u64,
+}
+
+struct Inner {
+    size:    usize,
+    tick:    u64,
+    entries: HashMap<String, Entry>,
+    lru:     BTreeMap<u64, String>, // last use to hash, oldest first
+    stats:   Stats,
+}
+
+/// least recently used block content, up to budget bytes.
+/// shared by all threads, the lock is not held while blocks are read from the store.
+pub struct BlockCache {
+    pub budget: usize,
+    pub verify: bool, // refuse blocks whose content doesn't match their hash
+    inner:   Mutex<Inner>,
+}
+
+pub fn new(budget: usize) -> BlockCache {
+    BlockCache{
+        budget:  budget,
+        verify:  true,
+        inner:   Mutex::new(Inner{
+            size:    0,
+            tick:    0,
+            entries: HashMap::new(),
+            lru:     BTreeMap::new(),
+            stats:   Stats::default(),
+        }),
+    }
+}
+
+impl BlockCache {
+    /// content of a block, read from blockstore if it isn't cached
+    pub fn get(&self, blockstore: &BlockStore, hash: &String) -> io::Result<Arc<Vec<u8>>> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(data) = inner.touch(hash) {
+                inner.stats.hits += 1;
+                return Ok(data);
+            }
+            inner.stats.misses += 1;
+        }
+        self.load(blockstore, hash)
+    }
+
+    /// load a block that will probably be read soon
+    pub fn readahead(&self, blockstore: &BlockStore, hash: &String) -> io::Result<()> {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.entries.contains_key(hash) {
+                return Ok(());
+            }
+            inner.stats.readahead += 1;
+        }
+        self.load(blockstore, hash).map(|_| ())
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.inner.lock().unwrap().stats
+    }
+
+    fn load(&self, blockstore: &BlockStore, hash: &String) -> io::Result<Arc<Vec<u8>>> {
+        let block = blockstore.get(hash)?;
+        let mut data = Vec::with_capacity(block.size);
+        block.chain().read_to_end(&mut data)?;
+        if self.verify && format!("{:x}", Sha512::digest(&data)) != *hash {
+            error!("block {} doesn't match its hash, not serving it", hash);
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("corrupt block {}", hash)));
+        }
+        let data = Arc::new(data);
+
+        // blocks larger than the whole budget are passed through
+        if data.len() > self.budget {
+            return Ok(data);
+        }
+        let mut inner = self.inner.lock().unwrap();
+
+        // another thread loaded it in the meantime
+        if let Some(data) = inner.touch(hash) {
+            return Ok(data);
+        }
+        while inner.size + data.len() > self.budget {
+            let oldest = match inner.lru.keys().next() {
+                Some(&t) => t,
+                None => break,
+            };
+            let h = inner.lru.remove(&oldest).unwrap();
+            let e = inner.entries.remove(&h).unwrap();
+            inner.size -= e.data.len();
+            inner.stats.evictions += 1;
+        }
+
+        inner.tick += 1;
+        inner.size += data.len();
+        let tick = inner.tick;
+        inner.lru.insert(tick, hash.clone());
+        inner.entries.insert(hash.clone(), Entry{
+            data: data.clone(),
+            used: tick,
+        });
+        Ok(data)
+    }
+}
+
+impl Inner {
+    fn touch(&mut self, hash: &String) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(hash) {
+            Some(e) => {
+                self.lru.remove(&e.used);
+                self.lru.insert(tick, hash.clone());
+                e.used = tick;
+                Some(e.data.clone())
+            },
+            None => None,
+        }
+    }
+}
+
+
+#[test]
+fn lru_budget() {
+    use blockstore::{memory, test_block};
+
+    let mut bs = memory();
+    bs.blocks.insert(String::from("a"),  test_block(vec![("test/readchain/a", 0, 4)]));
+    bs.blocks.insert(String::from("b"),  test_block(vec![("test/readchain/b", 0, 4)]));
+    bs.blocks.insert(String::from("c"),  test_block(vec![("test/readchain/b", 5, 5)]));
+    bs.blocks.insert(String::from("ab"), test_block(vec![("test/readchain/a", 0, 4), ("test/readchain/b", 0, 10)]));
+
+    let mut c = new(9);
+    c.verify = false;
+    assert_eq!(&c.get(&bs, &String::from("a")).unwrap()[..], b"yaya");
+    assert_eq!(&c.get(&bs, &String::from("b")).unwrap()[..], b"cool");
+    assert_eq!(&c.get(&bs, &String::from("a")).unwrap()[..], b"yaya");
+
+    // b is the least recently used
+    assert_eq!(&c.get(&bs, &String::from("c")).unwrap()[..], b"stuff");
+    assert_eq!(c.stats(), Stats{hits: 1, misses: 3, readahead: 0, evictions: 1});
+    c.get(&bs, &String::from("a")).unwrap();
+    c.get(&bs, &String::from("b")).unwrap();
+    assert_eq!(c.stats(), Stats{hits: 2, misses: 4, readahead: 0, evictions: 2});
+
+    // too large to be cached at all
+    assert_eq!(c.get(&bs, &String::from("ab")).unwrap().len(), 14);
+    c.get(&bs, &String::from("b")).unwrap();
+    assert_eq!(c.stats().hits, 3);
+
+    c.readahead(&bs, &String::from("c")).unwrap();
+    c.get(&bs, &String::from("c")).unwrap();
+    assert_eq!(c.stats(), Stats{hits: 4, misses: 5, readahead: 1, evictions: 3});
+
+    // checked blocks are only served under their own hash
+    let c = new(9);
+    let hash = format!("{:x}", Sha512::digest(b"yaya"));
+    bs.blocks.insert(hash.clone(), test_block(vec![("test/readchain/a", 0, 4)]));
+    assert_eq!(&c.get(&bs, &hash).unwrap()[..], b"yaya");
+    assert_eq!(c.get(&bs, &String::from("a")).unwrap_err().kind(), ErrorKind::InvalidData);
+}
@@ -0,0 +1,207 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use index::{Index, Inode, ContentDirEntry};
+use blockstore::BlockStore;
+use overlay::{self, WHITEOUT_PREFIX, OPAQUE};
+use serializer::Chunker;
+use tarball::new_inode;
+
+
+struct Commit<'a> {
+    parent:   &'a Index,
+    index:    Index,
+    copied:   HashMap<u64, u64>, // parent inode to new inode, to keep hardlinks
+    changed:  Vec<u64>,          // new inodes whose content is in the upper directory
+}
+
+impl<'a> Commit<'a> {
+    /// copy an unchanged subtree of the parent index
+    fn copy(&mut self, dir: u64, name: &OsString, old: &Inode) {
+        if let Some(&i) = self.copied.get(&old.i) {
+            self.index.inodes[dir as usize].d.get_or_insert(BTreeMap::new()).insert(name.clone(), ContentDirEntry{
+                i: i,
+                k: old.k,
+            });
+            return;
+        }
+        let i = new_inode(&mut self.index, dir, name.clone(), old.k, old.a as u32, old.u, old.g);
+        self.copied.insert(old.i, i);
+        {
+            let ref mut inode = self.index.inodes[i as usize];
+            inode.s = old.s;
+            inode.h = old.h.clone();
+            inode.c = old.c.clone();
+            inode.l = old.l.clone();
+        }
+        if let Some(ref d) = old.d {
+            let parent = self.parent;
+            for (n, e) in d {
+                self.copy(i, n, &parent.inodes[e.i as usize]);
+            }
+        }
+    }
+
+    /// merge the upper directory at path into the new directory dir
+    fn merge(&mut self, dir: u64, old: Option<&'a Inode>, path: &Path) -> io::Result<()> {
+        let opaque = path.join(OPAQUE).exists();
+        let mut names = BTreeSet::new();
+        let mut whiteouts = BTreeSet::new();
+        for e in fs::read_dir(path)? {
+            let name = e?.file_name();
+            if overlay::is_reserved(&name) {
+                whiteouts.insert(OsStr::from_bytes(&name.as_bytes()[WHITEOUT_PREFIX.len()..]).to_os_string());
+            } else {
+                names.insert(name);
+            }
+        }
+
+        // unchanged entries of the parent
+        if let (false, Some(old)) = (opaque, old) {
+            if let Some(ref d) = old.d {
+                for (name, e) in d {
+                    if !names.contains(name) && !whiteouts.contains(name) {
+                        let parent = self.parent;
+                        self.copy(dir, name, &parent.inodes[e.i as usize]);
+                    }
+                }
+            }
+        }
+
+        for name in names {
+            let p = path.join(&name);
+            let m = fs::symlink_metadata(&p)?;
+            let ft = m.file_type();
+            let kind = if ft.is_dir() { 1 } else if ft.is_symlink() { 3 } else { 2 };
+            let i = new_inode(&mut self.index, dir, name.clone(), kind, m.mode(), m.uid(), m.gid());
+            match kind {
+                1 => {
+                    let below = match old.and_then(|o| o.d.as_ref()).and_then(|d| d.get(&name)) {
+                        Some(e) if !opaque && e.k == 1 => Some(&self.parent.inodes[e.i as usize]),
+                        _ => None,
+                    };
+                    self.merge(i, below, &p)?;
+                },
+                3 => {
+                    let target = fs::read_link(&p)?.into_os_string();
+                    let ref mut inode = self.index.inodes[i as usize];
+                    inode.s = target.len() as u64;
+                    inode.l = Some(target);
+                },
+                _ => {
+                    let ref mut inode = self.index.inodes[i as usize];
+                    inode.s = m.len();
+                    inode.host_path = p.into_os_string();
+                    self.changed.push(i);
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// snapshot an overlay into a new index. only files in the upper directory are chunked,
+/// everything else keeps referencing the blocks of the parent index.
+/// blocks of changed files are added to blockstore, which should keep a copy of their content
+/// since files in the upper directory can still change. an encrypted image needs a chunker
+/// with the secret it was built with.
+pub fn commit(parent: &Index, upper: &Path, blockstore: &mut BlockStore, mut chunker: Chunker) -> io::Result<Index> {
+    let root = &parent.inodes[0];
+    let mut c = Commit{
+        parent:  parent,
+        index:   Index{
+            inodes: vec![Inode{
+                i: 0,
+                p: 0,
+                s: 0,
+                k: 1,
+                a: root.a,
+                u: root.u,
+                g: root.g,
+
+                d: Some(BTreeMap::new()),
+                h: None,
+                c: None,
+                l: None,
+
+                host_path: OsString::new(),
+            }],
+        },
+        copied:  HashMap::new(),
+        changed: Vec::new(),
+    };
+    c.merge(0, Some(root), upper)?;
+
+    let mut index = c.index;
+    for i in c.changed {
+        let file = BufReader::new(File::open(&index.inodes[i as usize].host_path)?);
+        chunker.add(&mut index, blockstore, i, file)?;
+    }
+    chunker.finish(&mut index, blockstore)?;
+    Ok(index)
+}
+
+
+#[test]
+fn commit_overlay() {
+    use std::ffi::OsStr;
+    use std::io::Read;
+
+    let mut bs = ::blockstore::memory();
+    let (tmp, parent) = ::tarball::fixture("commit", &mut bs);
+    let upper = tmp.join("upper");
+    fs::create_dir_all(&upper).unwrap();
+    let blocks = bs.blocks.len();
+
+    {
+        let mut fs = ::fs::Fuse::new(&parent, &bs);
+        fs.upper = Some(upper.clone());
+        let root = 1;
+        let etc = fs.do_lookup(root, OsStr::new("etc")).unwrap().ino;
+        let (_, fh) = fs.do_create(etc, OsStr::new("hosts"), 0o644, 0).unwrap();
+        fs.do_write(fh, 0, b"127.0.0.1 localhost\n").unwrap();
+        fs.do_unlink(root, OsStr::new("resolv.conf")).unwrap();
+    }
+
+    let index = commit(&parent, &upper, &mut bs, Chunker::new()).unwrap();
+    let root = index.inodes[0].d.as_ref().unwrap();
+    assert!(!root.contains_key(OsStr::new("resolv.conf")));
+    assert_eq!(index.inodes[0].a, parent.inodes[0].a);
+
+    let etc = &index.inodes[root[OsStr::new("etc")].i as usize];
+    assert_eq!(etc.a, 0o700);
+    let etc = etc.d.as_ref().unwrap();
+
+    // the hardlink survives and still shares the parents block
+    let usr = &index.inodes[root[OsStr::new("usr")].i as usize];
+    let share = &index.inodes[usr.d.as_ref().unwrap()[OsStr::new("share")].i as usize];
+    assert_eq!(share.d.as_ref().unwrap()[OsStr::new("resolv.conf")].i, etc[OsStr::new("resolv.conf")].i);
+    let old_etc = &parent.inodes[parent.inodes[0].d.as_ref().unwrap()[OsStr::new("etc")].i as usize];
+    let old_conf = &parent.inodes[old_etc.d.as_ref().unwrap()[OsStr::new("resolv.conf")].i as usize];
+    assert_eq!(index.inodes[etc[OsStr::new("resolv.conf")].i as usize].c.as_ref().unwrap()[0].h,
+               old_conf.c.as_ref().unwrap()[0].h);
+
+    let hosts = &index.inodes[etc[OsStr::new("hosts")].i as usize];
+    assert_eq!((hosts.s, hosts.a), (20, 0o644));
+    assert_eq!(bs.blocks.len(), blocks + 1);
+
+    let mut content = String::new();
+    hosts.chain(&bs).read_to_string(&mut content).unwrap();
+    assert_eq!(content, "127.0.0.1 localhost\n");
+
+    // with the secret of an encrypted image, changed files are encrypted too
+    let mut chunker = Chunker::new();
+    chunker.secret = Some([5; 32]);
+    let index = commit(&parent, &upper, &mut bs, chunker).unwrap();
+    let etc = &index.inodes[index.inodes[0].d.as_ref().unwrap()[OsStr::new("etc")].i as usize];
+    let hosts = &index.inodes[etc.d.as_ref().unwrap()[OsStr::new("hosts")].i as usize];
+    assert!(hosts.c.as_ref().unwrap()[0].e.is_some());
+    let mut content = String::new();
+    hosts.chain(&bs).read_to_string(&mut content).unwrap();
+    assert_eq!(content, "127.0.0.1 localhost\n");
+}
@@ -0,0 +1,280 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write, ErrorKind};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use sha2::{Sha512, Digest};
+
+
+pub const KEY_LEN: usize = 32;
+
+pub type Key = [u8; KEY_LEN];
+
+/// block keys are only ever used for the content they were derived from, so a fixed nonce is fine
+const BLOCK_NONCE: [u8; 12] = [0; 12];
+
+/// start of an index file encrypted with a master key
+const SEALED_MAGIC: &'static [u8] = b"cafs sealed index 1\n";
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(7);
+}
+
+fn le32(b: &[u8]) -> u32 {
+    b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
+
+/// one 64 byte block of chacha20 keystream, as in rfc 8439
+fn chacha20_block(key: &Key, nonce: &[u8; 12], counter: u32) -> [u8; 64] {
+    let mut init = [0u32; 16];
+    init[0] = 0x61707865;
+    init[1] = 0x3320646e;
+    init[2] = 0x79622d32;
+    init[3] = 0x6b206574;
+    for i in 0..8 {
+        init[4 + i] = le32(&key[i * 4..]);
+    }
+    init[12] = counter;
+    for i in 0..3 {
+        init[13 + i] = le32(&nonce[i * 4..]);
+    }
+
+    let mut s = init;
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+    let mut out = [0; 64];
+    for i in 0..16 {
+        let w = s[i].wrapping_add(init[i]);
+        out[i * 4]     = w as u8;
+        out[i * 4 + 1] = (w >> 8) as u8;
+        out[i * 4 + 2] = (w >> 16) as u8;
+        out[i * 4 + 3] = (w >> 24) as u8;
+    }
+    out
+}
+
+/// encrypt or decrypt data which starts at offset into the stream
+pub fn xor_at(key: &Key, nonce: &[u8; 12], offset: u64, data: &mut [u8]) {
+    let mut pos = offset;
+    let mut i = 0;
+    while i < data.len() {
+        let ks = chacha20_block(key, nonce, (pos / 64) as u32);
+        let from = (pos % 64) as usize;
+        let n = ::std::cmp::min(64 - from, data.len() - i);
+        for j in 0..n {
+            data[i + j] ^= ks[from + j];
+        }
+        i += n;
+        pos += n as u64;
+    }
+}
+
+pub fn hmac_sha512(key: &[u8], msg: &[u8]) -> [u8; 64] {
+    let mut k = [0u8; 128];
+    if key.len() > 128 {
+        k[..64].copy_from_slice(&Sha512::digest(key));
+    } else {
+        k[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha512::default();
+    inner.input(&k.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.input(msg);
+    let mut outer = Sha512::default();
+    outer.input(&k.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    outer.input(&inner.result());
+    let mut r = [0; 64];
+    r.copy_from_slice(&outer.result());
+    r
+}
+
+fn derive(secret: &[u8], label: &str) -> Key {
+    let mut k = [0; KEY_LEN];
+    k.copy_from_slice(&hmac_sha512(secret, label.as_bytes())[..KEY_LEN]);
+    k
+}
+
+/// the key a block is encrypted with depends only on the image secret and the content hash,
+/// so the same content still ends up as the same block
+pub fn block_key(secret: &Key, hash: &str) -> Key {
+    derive(secret, &format!("cafs block key {}", hash))
+}
+
+pub fn encrypt_block(key: &Key, data: &mut [u8]) {
+    xor_at(key, &BLOCK_NONCE, 0, data)
+}
+
+/// decrypt part of a block, which starts at offset into it
+pub fn decrypt_block_at(key: &Key, offset: u64, data: &mut [u8]) {
+    xor_at(key, &BLOCK_NONCE, offset, data)
+}
+
+pub fn random_key() -> io::Result<Key> {
+    let mut k = [0; KEY_LEN];
+    File::open("/dev/urandom")?.read_exact(&mut k)?;
+    Ok(k)
+}
+
+pub fn to_hex(b: &[u8]) -> String {
+    b.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "invalid hex digits");
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return Err(invalid());
+    }
+    (0..s.len() / 2).map(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| invalid())).collect()
+}
+
+pub fn key_from_hex(s: &str) -> io::Result<Key> {
+    let b = from_hex(s)?;
+    if b.len() != KEY_LEN {
+        return Err(io::Error::new(ErrorKind::InvalidData, "key is not 64 hex digits"));
+    }
+    let mut k = [0; KEY_LEN];
+    k.copy_from_slice(&b);
+    Ok(k)
+}
+
+/// the secret of an image in the key file at path, which is created with a new one if
+/// there is none yet. builds with the same secret give the same blocks for the same content.
+pub fn image_secret<P: AsRef<Path>>(path: P) -> io::Result<Key> {
+    let mut s = String::new();
+    match File::open(&path) {
+        Ok(mut f) => {
+            f.read_to_string(&mut s)?;
+            return key_from_hex(s.trim());
+        },
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {},
+        Err(e) => return Err(e),
+    }
+    let k = random_key()?;
+    let mut f = OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    writeln!(f, "{}", to_hex(&k))?;
+    Ok(k)
+}
+
+
+/// decrypts the block content read through r, which starts at offset into the block
+pub struct Decrypt<R: Read> {
+    inner: R,
+    key:   Option<Key>,
+    pos:   u64,
+}
+
+impl<R: Read> Decrypt<R> {
+    /// without a key, content is passed through as it is
+    pub fn new(inner: R, key: Option<Key>, offset: u64) -> Decrypt<R> {
+        Decrypt{
+            inner: inner,
+            key:   key,
+            pos:   offset,
+        }
+    }
+}
+
+impl<R: Read> Read for Decrypt<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(ref key) = self.key {
+            decrypt_block_at(key, self.pos, &mut buf[..n]);
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(SEALED_MAGIC)
+}
+
+/// encrypt and authenticate with a master key of any length
+pub fn seal(master: &[u8], mut data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut nonce = [0; 12];
+    nonce.copy_from_slice(&random_key()?[..12]);
+    xor_at(&derive(master, "cafs index encryption"), &nonce, 0, &mut data);
+    let mut signed = nonce.to_vec();
+    signed.extend_from_slice(&data);
+    let tag = hmac_sha512(&derive(master, "cafs index authentication"), &signed);
+
+    let mut r = SEALED_MAGIC.to_vec();
+    r.extend_from_slice(&tag);
+    r.extend_from_slice(&signed);
+    Ok(r)
+}
+
+pub fn open(master: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+    let header = SEALED_MAGIC.len() + 64 + 12;
+    if !is_sealed(sealed) || sealed.len() < header {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not a sealed index"));
+    }
+    let tag = &sealed[SEALED_MAGIC.len()..SEALED_MAGIC.len() + 64];
+    let signed = &sealed[SEALED_MAGIC.len() + 64..];
+    let expected = hmac_sha512(&derive(master, "cafs index authentication"), signed);
+    // compare without an early exit
+    if tag.iter().zip(expected.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) != 0 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "wrong master key or damaged index"));
+    }
+    let mut nonce = [0; 12];
+    nonce.copy_from_slice(&signed[..12]);
+    let mut data = signed[12..].to_vec();
+    xor_at(&derive(master, "cafs index encryption"), &nonce, 0, &mut data);
+    Ok(data)
+}
+
+
+#[test]
+fn test_vectors() {
+    // rfc 8439 2.4.2
+    let mut key = [0; KEY_LEN];
+    for i in 0..KEY_LEN {
+        key[i] = i as u8;
+    }
+    let nonce = [0, 0, 0, 0, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+    let mut data = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.".to_vec();
+    xor_at(&key, &nonce, 64, &mut data);
+    assert_eq!(to_hex(&data[..16]), "6e2e359a2568f98041ba0728dd0d6981");
+    assert_eq!(to_hex(&data[data.len() - 10..]), "b40b8eedf2785e42874d");
+
+    // decrypting from the middle of the stream
+    let mut tail = data[70..].to_vec();
+    xor_at(&key, &nonce, 64 + 70, &mut tail);
+    assert_eq!(&tail[..], &b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it."[70..]);
+
+    // rfc 4231 test case 2
+    assert_eq!(to_hex(&hmac_sha512(b"Jefe", b"what do ya want for nothing?")),
+               "164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7ea2505549758bf75c05a994a6d034f65f8f0e6fdcaeab1a34d4a6b4b636e070a38bce737");
+}
+
+#[test]
+fn sealing() {
+    let sealed = seal(b"master", b"{\"inodes\":[]}".to_vec()).unwrap();
+    assert!(is_sealed(&sealed));
+    assert!(!sealed.windows(6).any(|w| w == b"inodes"));
+    assert_eq!(open(b"master", &sealed).unwrap(), b"{\"inodes\":[]}");
+    assert_eq!(open(b"wrong", &sealed).unwrap_err().kind(), ErrorKind::InvalidData);
+
+    let mut damaged = sealed.clone();
+    let n = damaged.len() - 1;
+    damaged[n] ^= 1;
+    assert!(open(b"master", &damaged).is_err());
+
+    let k = random_key().unwrap();
+    assert_eq!(key_from_hex(&to_hex(&k)).unwrap(), k);
+    assert!(key_from_hex("00").is_err());
+
+    let tmp = ::tarball::TempDir::new("secret");
+    let secret = image_secret(tmp.join("key")).unwrap();
+    assert_eq!(image_secret(tmp.join("key")).unwrap(), secret);
+}
@@ -0,0 +1,141 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Write, ErrorKind};
+use std::path::Path;
+use sha2::{Sha512, Digest};
+use tar;
+
+use index::Index;
+use blockstore::{self, Block, BlockShard, BlockStore, CollisionPolicy};
+
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct MissingBlock {
+    pub h: String,  //block hash
+    pub s: u64,     //size, without a source only as far as the index references it
+}
+
+/// blocks referenced by index that are not in the local store, in the order they are first used.
+/// blocks can be shared with files of other images, so only source knows their whole size.
+pub fn missing(index: &Index, local: &BlockStore, source: Option<&BlockStore>) -> Vec<MissingBlock> {
+    let mut order = Vec::new();
+    let mut present = HashSet::new();
+    let mut sizes : HashMap<&String, u64> = HashMap::new();
+
+    for inode in &index.inodes {
+        if let Some(ref c) = inode.c {
+            for e in c {
+                // the local store is asked once per block, it may be remote
+                if present.contains(&e.h) {
+                    continue;
+                }
+                if !sizes.contains_key(&e.h) {
+                    if local.contains(&e.h) {
+                        present.insert(&e.h);
+                        continue;
+                    }
+                    order.push(&e.h);
+                }
+                let s = sizes.entry(&e.h).or_insert(0);
+                if e.o + e.l > *s {
+                    *s = e.o + e.l;
+                }
+            }
+        }
+    }
+
+    order.into_iter().map(|h| MissingBlock{
+        h: h.clone(),
+        s: source.and_then(|source| source.size(h)).map_or(sizes[h], |s| s as u64),
+    }).collect()
+}
+
+/// pack the given blocks into a tar archive with one file per block, named by its hash
+pub fn write_bundle<W: Write>(hashes: &[String], source: &BlockStore, w: W) -> io::Result<W> {
+    let mut b = tar::Builder::new(w);
+    for hash in hashes {
+        let block = source.get(hash)?;
+        let mut h = tar::Header::new_gnu();
+        h.set_mode(0o644);
+        h.set_uid(0);
+        h.set_gid(0);
+        h.set_mtime(0);
+        h.set_size(block.size as u64);
+        b.append_data(&mut h, hash, block.chain())?;
+    }
+    b.into_inner()
+}
+
+/// unpack a bundle into a directory of loose blocks. every block is checked against its hash
+/// before it is added to blockstore. returns the number of blocks added.
+pub fn read_bundle<R: Read>(r: R, blockstore: &mut BlockStore, blockdir: &Path) -> io::Result<usize> {
+    let mut archive = tar::Archive::new(r);
+    let mut count = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let hash = entry.path()?.to_string_lossy().into_owned();
+        if hash.is_empty() || !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("invalid block name {:?} in bundle", hash)));
+        }
+
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        if format!("{:x}", Sha512::digest(&content)) != hash {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("block {} in bundle is corrupt", hash)));
+        }
+
+        let path = blockdir.join(&hash);
+        if !path.exists() {
+            let tmp = blockdir.join(format!("{}.tmp", hash));
+            File::create(&tmp)?.write_all(&content)?;
+            fs::rename(&tmp, &path)?;
+        }
+        blockstore::insert(blockstore, hash, Block{
+            shards: vec![BlockShard{
+                file:   path.into_os_string(),
+                offset: 0,
+                size:   content.len(),
+                data:   None,
+            }],
+            size: content.len(),
+        }, CollisionPolicy::Error)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+
+#[test]
+fn delta_roundtrip() {
+    let tmp = ::tarball::TempDir::new("delta");
+    let source = tmp.join("source");
+    let device = tmp.join("device");
+    fs::create_dir_all(&source).unwrap();
+    fs::create_dir_all(&device).unwrap();
+
+    let mut bs = blockstore::memory();
+    let index = ::tarball::from_tar(&::tarball::tar_fixture()[..], &mut bs, &source).unwrap();
+
+    let mut local = blockstore::loose(&device).unwrap();
+    let want = missing(&index, &local, Some(&bs));
+    assert_eq!(want.len(), bs.blocks.len());
+    assert_eq!(want.iter().fold(0, |acc, m| acc + m.s), 21);
+
+    // an image using only the start of a block
+    let mut partial = Index{
+        inodes: index.inodes.clone(),
+    };
+    for inode in partial.inodes.iter_mut() {
+        for e in inode.c.iter_mut().flat_map(|c| c.iter_mut()) {
+            e.l = 5;
+        }
+    }
+    assert_eq!(missing(&partial, &local, None).iter().fold(0, |acc, m| acc + m.s), 5);
+    assert_eq!(missing(&partial, &local, Some(&bs)), want);
+
+    let hashes : Vec<String> = want.into_iter().map(|m| m.h).collect();
+    let bundle = write_bundle(&hashes, &bs, Vec::new()).unwrap();
+    assert_eq!(read_bundle(&bundle[..], &mut local, &device).unwrap(), hashes.len());
+    assert_eq!(missing(&index, &local, None), vec![]);
+    assert_eq!(local.list().len(), hashes.len());
+}
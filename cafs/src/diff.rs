@@ -0,0 +1,234 @@
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+
+use index::{Index, Inode};
+use merkle;
+use overlay;
+
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Added,
+    Removed,
+    Modified, // content or kind changed
+    Metadata, // only mode or owner changed
+}
+
+#[derive(Serialize, Debug)]
+pub struct Entry {
+    pub path:   String,
+    pub change: Change,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Diff {
+    pub changes: Vec<Entry>,
+
+    pub new_blocks:     usize, // blocks only referenced by the new index
+    pub shared_blocks:  usize, // blocks referenced by both
+    pub removed_blocks: usize, // blocks only referenced by the old index
+}
+
+/// every path in the index, relative to the root which is "."
+fn paths(index: &Index) -> BTreeMap<OsString, &Inode> {
+    fn descend<'a>(index: &'a Index, inode: &'a Inode, path: &OsStr, r: &mut BTreeMap<OsString, &'a Inode>) {
+        if let Some(ref d) = inode.d {
+            for (name, e) in d {
+                let child = &index.inodes[e.i as usize];
+                let p = match path.to_str() {
+                    Some(".") => name.clone(),
+                    _         => overlay::join(path, name),
+                };
+                if child.k == 1 {
+                    descend(index, child, &p, r);
+                }
+                r.insert(p, child);
+            }
+        }
+    }
+    let mut r = BTreeMap::new();
+    r.insert(OsString::from("."), &index.inodes[0]);
+    descend(index, &index.inodes[0], OsStr::new("."), &mut r);
+    r
+}
+
+fn blocks(index: &Index) -> HashSet<&String> {
+    index.inodes.iter()
+        .filter_map(|i| i.c.as_ref())
+        .flat_map(|c| c.iter().map(|b| &b.h))
+        .collect()
+}
+
+fn same_content(a: &Inode, b: &Inode) -> bool {
+    if a.k != b.k || a.s != b.s || a.l != b.l {
+        return false;
+    }
+    if a.k != 2 {
+        return true;
+    }
+    // a block can span files, so an unchanged file can end up in other blocks when
+    // the file before it changed. its own hash is what counts, where there is one.
+    if let (&Some(ref ha), &Some(ref hb)) = (&a.h, &b.h) {
+        return ha == hb;
+    }
+    let empty = Vec::new();
+    let ca = a.c.as_ref().unwrap_or(&empty);
+    let cb = b.c.as_ref().unwrap_or(&empty);
+    ca.len() == cb.len() && ca.iter().zip(cb.iter()).all(|(x, y)| x.h == y.h && x.o == y.o && x.l == y.l)
+}
+
+/// what changed from index a to index b
+pub fn diff(a: &Index, b: &Index) -> Diff {
+    let ba = blocks(a);
+    let bb = blocks(b);
+    let mut d = Diff {
+        changes: Vec::new(),
+        new_blocks:     bb.difference(&ba).count(),
+        shared_blocks:  bb.intersection(&ba).count(),
+        removed_blocks: ba.difference(&bb).count(),
+    };
+
+    // the same root hash means the same tree, no need to walk it
+    match (merkle::root(a), merkle::root(b)) {
+        (Ok(ref ra), Ok(ref rb)) if ra == rb => {},
+        _ => d.changes = changes(a, b),
+    }
+    d
+}
+
+fn changes(a: &Index, b: &Index) -> Vec<Entry> {
+    let pa = paths(a);
+    let pb = paths(b);
+
+    let mut changes = Vec::new();
+    for (path, ia) in &pa {
+        let change = match pb.get(path) {
+            None => Some(Change::Removed),
+            Some(ib) => {
+                if !same_content(ia, ib) {
+                    Some(Change::Modified)
+                } else if ia.a != ib.a || ia.u != ib.u || ia.g != ib.g {
+                    Some(Change::Metadata)
+                } else {
+                    None
+                }
+            },
+        };
+        if let Some(change) = change {
+            changes.push(Entry{
+                path: path.to_string_lossy().into_owned(),
+                change: change,
+            });
+        }
+    }
+    for path in pb.keys() {
+        if !pa.contains_key(path) {
+            changes.push(Entry{
+                path: path.to_string_lossy().into_owned(),
+                change: Change::Added,
+            });
+        }
+    }
+    changes.sort_by(|x, y| x.path.cmp(&y.path));
+    changes
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for e in &self.changes {
+            let c = match e.change {
+                Change::Added    => "added",
+                Change::Removed  => "removed",
+                Change::Modified => "modified",
+                Change::Metadata => "metadata",
+            };
+            writeln!(f, "{:9} {}", c, e.path)?;
+        }
+        writeln!(f, "{} new blocks, {} shared blocks, {} removed blocks",
+                 self.new_blocks, self.shared_blocks, self.removed_blocks)
+    }
+}
+
+
+#[test]
+fn diff_fixture() {
+    use index::{ContentBlockEntry, ContentDirEntry};
+
+    let mut bs = ::blockstore::memory();
+    let (_blockdir, a) = ::tarball::fixture("diff", &mut bs);
+
+    let mut b = Index{
+        inodes: a.inodes.clone(),
+    };
+    let root = b.inodes[0].d.as_ref().unwrap().clone();
+
+    // metadata only
+    b.inodes[root[OsStr::new("etc")].i as usize].a = 0o755;
+
+    // new content for a hardlinked file shows up under both names
+    let conf = b.inodes[root[OsStr::new("etc")].i as usize].d.as_ref().unwrap()[OsStr::new("resolv.conf")].i;
+    b.inodes[conf as usize].c = Some(vec![ContentBlockEntry{
+        h: String::from("new"),
+        o: 0,
+        l: 21,
+        e: None,
+    }]);
+    b.inodes[conf as usize].h = Some(String::from("new"));
+
+    // symlink replaced with a directory
+    let i = b.inodes.len() as u64;
+    let mut dir = b.inodes[0].clone();
+    dir.i = i;
+    dir.d = None;
+    b.inodes.push(dir);
+    b.inodes[0].d.as_mut().unwrap().insert(OsString::from("resolv.conf"), ContentDirEntry{i: i, k: 1});
+
+    // removed subtree
+    b.inodes[0].d.as_mut().unwrap().remove(OsStr::new("usr"));
+
+    let d = diff(&a, &b);
+    let changes : Vec<(&str, Change)> = d.changes.iter().map(|e| (e.path.as_str(), e.change)).collect();
+    assert_eq!(changes, vec![
+        ("etc", Change::Metadata),
+        ("etc/resolv.conf", Change::Modified),
+        ("resolv.conf", Change::Modified),
+        ("usr", Change::Removed),
+        ("usr/share", Change::Removed),
+        ("usr/share/resolv.conf", Change::Removed),
+    ]);
+    assert_eq!((d.new_blocks, d.shared_blocks, d.removed_blocks), (1, 0, 1));
+
+    let text = format!("{}", d);
+    assert!(text.starts_with("metadata  etc\nmodified  etc/resolv.conf\n"));
+    assert!(text.ends_with("1 new blocks, 0 shared blocks, 1 removed blocks\n"));
+
+    let d = diff(&a, &a);
+    assert!(d.changes.is_empty());
+    assert_eq!((d.new_blocks, d.shared_blocks, d.removed_blocks), (0, 1, 0));
+}
+
+#[test]
+fn unchanged_neighbour() {
+    use std::fs::File;
+    use std::io::Write;
+
+    let tmp = ::tarball::TempDir::new("diff-neighbour");
+    let build = |first: &[u8]| {
+        File::create(tmp.join("a")).unwrap().write_all(first).unwrap();
+        File::create(tmp.join("b")).unwrap().write_all(b"unchanged").unwrap();
+        let mut index = ::index::from_host(tmp.as_os_str().to_os_string());
+        index.serialize(&mut ::blockstore::memory()).unwrap();
+        index
+    };
+    let a = build(b"first version");
+    let b = build(b"second version");
+
+    // both files are in one block, so b's block changed along with a
+    let blocks = |index: &Index| index.inodes[index.inodes[0].d.as_ref().unwrap()[OsStr::new("b")].i as usize].c.clone().unwrap()[0].h.clone();
+    assert!(blocks(&a) != blocks(&b));
+
+    let changes : Vec<(String, Change)> = diff(&a, &b).changes.into_iter().map(|e| (e.path, e.change)).collect();
+    assert_eq!(changes, vec![(String::from("a"), Change::Modified)]);
+}
@@ -0,0 +1,391 @@
+use sha2::{Sha512, Digest};
+
+
+// ed25519 as in rfc 8032, following the field and group arithmetic of tweetnacl.
+// fine for signing and checking index files, it doesn't try to hide timing.
+// it's here instead of a crate because sha2 is the only crypto dependency we take,
+// and it's small enough to check against the rfc vectors in the tests below.
+// verifying only ever sees public data (key, signature, index root), so timing
+// doesn't leak anything there. signing runs once per build on the signer's machine.
+
+pub type Seed = [u8; 32];
+pub type PublicKey = [u8; 32];
+pub type Signature = [u8; 64];
+
+type Gf = [i64; 16];
+type Point = [Gf; 4];
+
+const GF0: Gf = [0; 16];
+const GF1: Gf = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+const D: Gf = [0x78a3, 0x1359, 0x4dca, 0x75eb, 0xd8ab, 0x4141, 0x0a4d, 0x0070,
+               0xe898, 0x7779, 0x4079, 0x8cc7, 0xfe73, 0x2b6f, 0x6cee, 0x5203];
+const D2: Gf = [0xf159, 0x26b2, 0x9b94, 0xebd6, 0xb156, 0x8283, 0x149a, 0x00e0,
+                0xd130, 0xeef3, 0x80f2, 0x198e, 0xfce7, 0x56df, 0xd9dc, 0x2406];
+const X: Gf = [0xd51a, 0x8f25, 0x2d60, 0xc956, 0xa7b2, 0x9525, 0xc760, 0x692c,
+               0xdc5c, 0xfdd6, 0xe231, 0xc0a4, 0x53fe, 0xcd6e, 0x36d3, 0x2169];
+const Y: Gf = [0x6658, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+               0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666];
+const I: Gf = [0xa0b0, 0x4a0e, 0x1b27, 0xc4ee, 0xe478, 0xad2f, 0x1806, 0x2f43,
+               0xd7a7, 0x3dfb, 0x0099, 0x2b4d, 0xdf0b, 0x4fc1, 0x2480, 0x2b83];
+
+// order of the base point
+const L: [i64; 32] = [0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14,
+                      0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10];
+
+fn car(o: &mut Gf) {
+    for i in 0..16 {
+        o[i] += 1 << 16;
+        let c = o[i] >> 16;
+        if i < 15 {
+            o[i + 1] += c - 1;
+        } else {
+            o[0] += 38 * (c - 1);
+        }
+        o[i] -= c << 16;
+    }
+}
+
+/// swap p and q if b is 1
+fn sel(p: &mut Gf, q: &mut Gf, b: i64) {
+    let c = !(b - 1);
+    for i in 0..16 {
+        let t = c & (p[i] ^ q[i]);
+        p[i] ^= t;
+        q[i] ^= t;
+    }
+}
+
+fn pack25519(n: &Gf) -> [u8; 32] {
+    let mut t = *n;
+    car(&mut t);
+    car(&mut t);
+    car(&mut t);
+    for _ in 0..2 {
+        let mut m = GF0;
+        m[0] = t[0] - 0xffed;
+        for i in 1..15 {
+            m[i] = t[i] - 0xffff - ((m[i - 1] >> 16) & 1);
+            m[i - 1] &= 0xffff;
+        }
+        m[15] = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        let b = (m[15] >> 16) & 1;
+        m[14] &= 0xffff;
+        sel(&mut t, &mut m, 1 - b);
+    }
+    let mut o = [0; 32];
+    for i in 0..16 {
+        o[2 * i]     = t[i] as u8;
+        o[2 * i + 1] = (t[i] >> 8) as u8;
+    }
+    o
+}
+
+fn unpack25519(n: &[u8]) -> Gf {
+    let mut o = GF0;
+    for i in 0..16 {
+        o[i] = n[2 * i] as i64 + ((n[2 * i + 1] as i64) << 8);
+    }
+    o[15] &= 0x7fff;
+    o
+}
+
+fn neq(a: &Gf, b: &Gf) -> bool {
+    pack25519(a) != pack25519(b)
+}
+
+fn parity(a: &Gf) -> u8 {
+    pack25519(a)[0] & 1
+}
+
+fn add(a: &Gf, b: &Gf) -> Gf {
+    let mut o = GF0;
+    for i in 0..16 {
+        o[i] = a[i] + b[i];
+    }
+    o
+}
+
+fn sub(a: &Gf, b: &Gf) -> Gf {
+    let mut o = GF0;
+    for i in 0..16 {
+        o[i] = a[i] - b[i];
+    }
+    o
+}
+
+fn mul(a: &Gf, b: &Gf) -> Gf {
+    let mut t = [0i64; 31];
+    for i in 0..16 {
+        for j in 0..16 {
+            t[i + j] += a[i] * b[j];
+        }
+    }
+    for i in 0..15 {
+        t[i] += 38 * t[i + 16];
+    }
+    let mut o = GF0;
+    o.copy_from_slice(&t[..16]);
+    car(&mut o);
+    car(&mut o);
+    o
+}
+
+fn square(a: &Gf) -> Gf {
+    mul(a, a)
+}
+
+fn inverse(i: &Gf) -> Gf {
+    let mut c = *i;
+    for a in (0..254).rev() {
+        c = square(&c);
+        if a != 2 && a != 4 {
+            c = mul(&c, i);
+        }
+    }
+    c
+}
+
+fn pow2523(i: &Gf) -> Gf {
+    let mut c = *i;
+    for a in (0..251).rev() {
+        c = square(&c);
+        if a != 1 {
+            c = mul(&c, i);
+        }
+    }
+    c
+}
+
+/// p += q
+fn point_add(p: &mut Point, q: &Point) {
+    let a = mul(&sub(&p[1], &p[0]), &sub(&q[1], &q[0]));
+    let b = mul(&add(&p[0], &p[1]), &add(&q[0], &q[1]));
+    let c = mul(&mul(&p[3], &q[3]), &D2);
+    let d = mul(&p[2], &q[2]);
+    let d = add(&d, &d);
+    let e = sub(&b, &a);
+    let f = sub(&d, &c);
+    let g = add(&d, &c);
+    let h = add(&b, &a);
+
+    p[0] = mul(&e, &f);
+    p[1] = mul(&h, &g);
+    p[2] = mul(&g, &f);
+    p[3] = mul(&e, &h);
+}
+
+fn cswap(p: &mut Point, q: &mut Point, b: i64) {
+    for i in 0..4 {
+        sel(&mut p[i], &mut q[i], b);
+    }
+}
+
+fn pack(p: &Point) -> [u8; 32] {
+    let zi = inverse(&p[2]);
+    let tx = mul(&p[0], &zi);
+    let ty = mul(&p[1], &zi);
+    let mut r = pack25519(&ty);
+    r[31] ^= parity(&tx) << 7;
+    r
+}
+
+fn scalarmult(mut q: Point, s: &[u8]) -> Point {
+    let mut p = [GF0, GF1, GF1, GF0];
+    for i in (0..256).rev() {
+        let b = ((s[i / 8] >> (i & 7)) & 1) as i64;
+        cswap(&mut p, &mut q, b);
+        point_add(&mut q, &p);
+        let pp = p;
+        point_add(&mut p, &pp);
+        cswap(&mut p, &mut q, b);
+    }
+    p
+}
+
+fn scalarbase(s: &[u8]) -> Point {
+    scalarmult([X, Y, GF1, mul(&X, &Y)], s)
+}
+
+/// the negated point encoded in p, if it is one
+fn unpackneg(p: &[u8; 32]) -> Option<Point> {
+    let y = unpack25519(p);
+    let num = square(&y);
+    let den = mul(&num, &D);
+    let num = sub(&num, &GF1);
+    let den = add(&GF1, &den);
+
+    let den2 = square(&den);
+    let den4 = square(&den2);
+    let den6 = mul(&den4, &den2);
+    let t = mul(&mul(&den6, &num), &den);
+    let t = mul(&mul(&pow2523(&t), &num), &den);
+    let mut x = mul(&mul(&t, &den), &den);
+
+    if neq(&mul(&square(&x), &den), &num) {
+        x = mul(&x, &I);
+    }
+    if neq(&mul(&square(&x), &den), &num) {
+        return None;
+    }
+    if parity(&x) == p[31] >> 7 {
+        x = sub(&GF0, &x);
+    }
+    let t = mul(&x, &y);
+    Some([x, y, GF1, t])
+}
+
+/// x mod L, x being 64 limbs of 8 bits
+fn mod_l(x: &mut [i64; 64]) -> [u8; 32] {
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        let mut j = i - 32;
+        while j < i - 12 {
+            x[j] += carry - 16 * x[i] * L[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+            j += 1;
+        }
+        x[j] += carry;
+        x[i] = 0;
+    }
+    let mut carry = 0;
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * L[j];
+        carry = x[j] >> 8;
+        x[j] &= 255;
+    }
+    for j in 0..32 {
+        x[j] -= carry * L[j];
+    }
+    let mut r = [0; 32];
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        r[i] = (x[i] & 255) as u8;
+    }
+    r
+}
+
+fn reduce(h: &[u8]) -> [u8; 32] {
+    let mut x = [0i64; 64];
+    for i in 0..64 {
+        x[i] = h[i] as i64;
+    }
+    mod_l(&mut x)
+}
+
+fn hash(parts: &[&[u8]]) -> Vec<u8> {
+    let mut h = Sha512::default();
+    for p in parts {
+        h.input(p);
+    }
+    h.result().to_vec()
+}
+
+/// secret scalar and nonce prefix of a seed
+fn expand(seed: &Seed) -> Vec<u8> {
+    let mut d = hash(&[seed]);
+    d[0]  &= 248;
+    d[31] &= 127;
+    d[31] |= 64;
+    d
+}
+
+pub fn public_key(seed: &Seed) -> PublicKey {
+    pack(&scalarbase(&expand(seed)[..32]))
+}
+
+pub fn sign(seed: &Seed, msg: &[u8]) -> Signature {
+    let d = expand(seed);
+    let pk = public_key(seed);
+    let r = reduce(&hash(&[&d[32..], msg]));
+    let big_r = pack(&scalarbase(&r));
+    let h = reduce(&hash(&[&big_r, &pk, msg]));
+
+    let mut x = [0i64; 64];
+    for i in 0..32 {
+        x[i] = r[i] as i64;
+    }
+    for i in 0..32 {
+        for j in 0..32 {
+            x[i + j] += h[i] as i64 * d[j] as i64;
+        }
+    }
+    let mut sig = [0; 64];
+    sig[..32].copy_from_slice(&big_r);
+    sig[32..].copy_from_slice(&mod_l(&mut x));
+    sig
+}
+
+/// whether the little endian scalar s is below the group order
+fn reduced(s: &[u8]) -> bool {
+    for i in (0..32).rev() {
+        if (s[i] as i64) != L[i] {
+            return (s[i] as i64) < L[i];
+        }
+    }
+    false
+}
+
+pub fn verify(pk: &PublicKey, msg: &[u8], sig: &Signature) -> bool {
+    // s has to be below L, otherwise s + L would be another valid signature
+    if !reduced(&sig[32..]) {
+        return false;
+    }
+    let q = match unpackneg(pk) {
+        Some(q) => q,
+        None => return false,
+    };
+    let h = reduce(&hash(&[&sig[..32], pk, msg]));
+    let mut p = scalarmult(q, &h);
+    point_add(&mut p, &scalarbase(&sig[32..]));
+    pack(&p)[..] == sig[..32]
+}
+
+
+#[test]
+fn rfc8032_vectors() {
+    use crypto::{to_hex, from_hex};
+
+    let vectors = [
+        ("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+         "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+         "",
+         "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b"),
+        ("4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+         "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+         "72",
+         "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00"),
+    ];
+    for &(seed, pk, msg, sig) in vectors.iter() {
+        let mut s = [0; 32];
+        s.copy_from_slice(&from_hex(seed).unwrap());
+        let msg = from_hex(msg).unwrap();
+        assert_eq!(to_hex(&public_key(&s)), pk);
+        let signature = sign(&s, &msg);
+        assert_eq!(to_hex(&signature[..]), sig);
+        assert!(verify(&public_key(&s), &msg, &signature));
+
+        let mut bad = signature;
+        bad[5] ^= 4;
+        assert!(!verify(&public_key(&s), &msg, &bad));
+        assert!(!verify(&public_key(&s), b"other", &signature));
+
+        // the same signature with s + L
+        let mut malleated = signature;
+        let mut carry = 0;
+        for i in 0..32 {
+            let v = malleated[32 + i] as i64 + L[i] + carry;
+            malleated[32 + i] = v as u8;
+            carry = v >> 8;
+        }
+        assert!(!verify(&public_key(&s), &msg, &malleated));
+    }
+    assert!(reduced(&[0; 32]));
+    let mut l = [0; 32];
+    for i in 0..32 {
+        l[i] = L[i] as u8;
+    }
+    assert!(!reduced(&l));
+    l[0] -= 1;
+    assert!(reduced(&l));
+}
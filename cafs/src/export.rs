@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, File, Permissions};
+use std::io::{self, Write, BufWriter};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use libc;
+use tar;
+
+use index::{Index, Inode};
+use blockstore::BlockStore;
+
+
+/// directory entries of an inode sorted by name, so exports are reproducible
+fn sorted_entries(inode: &Inode) -> Vec<(&OsString, u64)> {
+    let mut entries : Vec<(&OsString, u64)> = match inode.d {
+        None => Vec::new(),
+        Some(ref d) => d.iter().map(|(name, e)| (name, e.i)).collect(),
+    };
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    entries
+}
+
+pub fn lchown(path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+    let p = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::lchown(p.as_ptr(), uid, gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+struct DirExport<'a> {
+    index:      &'a Index,
+    blockstore: &'a BlockStore,
+    chown:      bool,
+    exported:   HashMap<u64, PathBuf>, // first path of every file, to recreate hardlinks
+}
+
+impl<'a> DirExport<'a> {
+    fn descend(&mut self, dir: &Inode, path: &Path) -> io::Result<()> {
+        for (name, i) in sorted_entries(dir) {
+            let inode = &self.index.inodes[i as usize];
+            let target = path.join(name);
+            match inode.k {
+                1 => {
+                    fs::create_dir_all(&target)?;
+                    self.descend(inode, &target)?;
+                },
+                3 => {
+                    if let Ok(_) = fs::symlink_metadata(&target) {
+                        fs::remove_file(&target)?;
+                    }
+                    symlink(inode.l.as_ref().map(|l| l.as_os_str()).unwrap_or(OsStr::new("")), &target)?;
+                },
+                _ => {
+                    if let Some(first) = self.exported.get(&inode.i) {
+                        if let Ok(_) = fs::symlink_metadata(&target) {
+                            fs::remove_file(&target)?;
+                        }
+                        fs::hard_link(first, &target)?;
+                        continue;
+                    }
+                    let mut f = BufWriter::new(File::create(&target)?);
+                    io::copy(&mut inode.chain(self.blockstore), &mut f)?;
+                    f.flush()?;
+                    self.exported.insert(inode.i, target.clone());
+                },
+            }
+            self.set_meta(inode, &target)?;
+        }
+        Ok(())
+    }
+
+    fn set_meta(&self, inode: &Inode, path: &Path) -> io::Result<()> {
+        // chown before chmod, since chown clears setuid bits
+        if self.chown {
+            lchown(path, inode.u, inode.g)?;
+        }
+        if inode.k != 3 {
+            fs::set_permissions(path, Permissions::from_mode(inode.a as u32))?;
+        }
+        Ok(())
+    }
+}
+
+/// recreate the tree below the index root in dir. ownership is only restored when running as root.
+pub fn to_dir<P: AsRef<Path>>(index: &Index, blockstore: &BlockStore, dir: P) -> io::Result<()> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+
+    let mut e = DirExport{
+        index:      index,
+        blockstore: blockstore,
+        chown:      unsafe { libc::geteuid() } == 0,
+        exported:   HashMap::new(),
+    };
+    let root = &index.inodes[0];
+    e.descend(root, dir)?;
+    e.set_meta(root, dir)
+}
+
+
+fn header(inode: &Inode, kind: tar::EntryType, size: u64) -> tar::Header {
+    let mut h = tar::Header::new_gnu();
+    h.set_entry_type(kind);
+    h.set_mode(inode.a as u32);
+    h.set_uid(inode.u as u64);
+    h.set_gid(inode.g as u64);
+    h.set_mtime(0);
+    h.set_size(size);
+    h
+}
+
+fn tar_descend<W: Write>(b: &mut tar::Builder<W>, index: &Index, blockstore: &BlockStore,
+                         dir: &Inode, path: &Path, exported: &mut HashMap<u64, PathBuf>) -> io::Result<()> {
+    for (name, i) in sorted_entries(dir) {
+        let inode = &index.inodes[i as usize];
+        let target = path.join(name);
+        match inode.k {
+            1 => {
+                let mut h = header(inode, tar::EntryType::Directory, 0);
+                b.append_data(&mut h, &target, io::empty())?;
+                tar_descend(b, index, blockstore, inode, &target, exported)?;
+            },
+            3 => {
+                let mut h = header(inode, tar::EntryType::Symlink, 0);
+                b.append_link(&mut h, &target, inode.l.as_ref().map(|l| l.as_os_str()).unwrap_or(OsStr::new("")))?;
+            },
+            _ => {
+                if let Some(first) = exported.get(&inode.i) {
+                    let mut h = header(inode, tar::EntryType::Link, 0);
+                    b.append_link(&mut h, &target, first)?;
+                    continue;
+                }
+                let mut h = header(inode, tar::EntryType::Regular, inode.s);
+                b.append_data(&mut h, &target, inode.chain(blockstore))?;
+                exported.insert(inode.i, target);
+            },
+        }
+    }
+    Ok(())
+}
+
+/// write the image as a tar archive with paths relative to "./"
+pub fn to_tar<W: Write>(index: &Index, blockstore: &BlockStore, w: W) -> io::Result<W> {
+    let mut b = tar::Builder::new(w);
+    let root = &index.inodes[0];
+    let mut h = header(root, tar::EntryType::Directory, 0);
+    b.append_data(&mut h, ".", io::empty())?;
+    tar_descend(&mut b, index, blockstore, root, Path::new("."), &mut HashMap::new())?;
+    b.into_inner()
+}
+
+
+#[test]
+fn export_dir() {
+    use std::io::Read;
+    use std::os::unix::fs::MetadataExt;
+
+    let mut bs = ::blockstore::memory();
+    let (tmp, index) = ::tarball::fixture("export-dir", &mut bs);
+    let out = tmp.join("out");
+    to_dir(&index, &bs, &out).unwrap();
+
+    let mut content = String::new();
+    File::open(out.join("etc/resolv.conf")).unwrap().read_to_string(&mut content).unwrap();
+    assert_eq!(content, "nameserver 127.0.0.1\n");
+
+    let a = fs::metadata(out.join("etc/resolv.conf")).unwrap();
+    let b = fs::metadata(out.join("usr/share/resolv.conf")).unwrap();
+    assert_eq!(a.ino(), b.ino());
+    assert_eq!(a.mode() & 0o7777, 0o640);
+    assert_eq!(fs::metadata(out.join("etc")).unwrap().mode() & 0o7777, 0o700);
+    assert_eq!(fs::read_link(out.join("resolv.conf")).unwrap(), Path::new("etc/resolv.conf"));
+}
+
+#[test]
+fn export_tar_roundtrip() {
+    use std::ffi::OsStr;
+    use std::io::Read;
+
+    let mut bs = ::blockstore::memory();
+    let (tmp, index) = ::tarball::fixture("export-tar", &mut bs);
+    let archive = to_tar(&index, &bs, Vec::new()).unwrap();
+
+    let mut bs2 = ::blockstore::memory();
+    let index2 = ::tarball::from_tar(&archive[..], &mut bs2, &tmp).unwrap();
+
+    let root = index2.inodes[0].d.as_ref().unwrap();
+    let etc  = &index2.inodes[root[OsStr::new("etc")].i as usize];
+    assert_eq!(etc.a, 0o700);
+    assert_eq!((etc.u, etc.g), (12, 34));
+
+    let conf = etc.d.as_ref().unwrap()[OsStr::new("resolv.conf")].i;
+    let usr  = &index2.inodes[root[OsStr::new("usr")].i as usize];
+    let share = &index2.inodes[usr.d.as_ref().unwrap()[OsStr::new("share")].i as usize];
+    assert_eq!(share.d.as_ref().unwrap()[OsStr::new("resolv.conf")].i, conf);
+    assert_eq!(index2.inodes[root[OsStr::new("resolv.conf")].i as usize].l, Some(OsString::from("etc/resolv.conf")));
+
+    let mut content = String::new();
+    index2.inodes[conf as usize].chain(&bs2).read_to_string(&mut content).unwrap();
+    assert_eq!(content, "nameserver 127.0.0.1\n");
+}
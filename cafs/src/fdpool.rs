@@ -0,0 +1,211 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, ErrorKind};
+use std::os::unix::fs::FileExt;
+use std::sync::{Arc, Mutex};
+
+
+pub const DEFAULT_CAPACITY: usize = 256;
+
+lazy_static! {
+    /// shared by all block stores, so the number of open files stays bounded no matter how many blocks are read
+    pub static ref POOL: FdPool = new(DEFAULT_CAPACITY);
+}
+
+struct Inner {
+    capacity: usize,
+    tick:     u64,
+    files:    HashMap<OsString, (Arc<File>, u64)>,
+    lru:      BTreeMap<u64, OsString>, // last use to path, oldest first
+    opens:    u64,
+}
+
+/// read only file descriptors by path, closing the least recently used ones above capacity.
+/// descriptors handed out stay valid until the last reader drops them, even when evicted.
+pub struct FdPool {
+    inner: Mutex<Inner>,
+}
+
+pub fn new(capacity: usize) -> FdPool {
+    FdPool{
+        inner: Mutex::new(Inner{
+            capacity: capacity,
+            tick:     0,
+            files:    HashMap::new(),
+            lru:      BTreeMap::new(),
+            opens:    0,
+        }),
+    }
+}
+
+impl FdPool {
+    pub fn open(&self, path: &OsString) -> io::Result<Arc<File>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let hit = match inner.files.get_mut(path) {
+            Some(&mut (ref f, ref mut used)) => {
+                let old = *used;
+                *used = tick;
+                Some((f.clone(), old))
+            },
+            None => None,
+        };
+        if let Some((f, old)) = hit {
+            inner.lru.remove(&old);
+            inner.lru.insert(tick, path.clone());
+            return Ok(f);
+        }
+
+        let f = Arc::new(File::open(path)?);
+        inner.opens += 1;
+        while inner.files.len() >= inner.capacity {
+            let oldest = match inner.lru.keys().next() {
+                Some(&t) => t,
+                None => break,
+            };
+            let p = inner.lru.remove(&oldest).unwrap();
+            inner.files.remove(&p);
+        }
+        inner.files.insert(path.clone(), (f.clone(), tick));
+        inner.lru.insert(tick, path.clone());
+        Ok(f)
+    }
+
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.capacity = ::std::cmp::max(capacity, 1);
+    }
+
+    #[cfg(test)]
+    /// number of descriptors currently held
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().files.len()
+    }
+
+    #[cfg(test)]
+    /// number of times a file had to be opened
+    pub fn opens(&self) -> u64 {
+        self.inner.lock().unwrap().opens
+    }
+}
+
+
+/// reads a range of a file with pread, so readers of the same file can share a descriptor.
+/// the file is only opened on the first read.
+pub struct RangeReader<'p> {
+    pool:   &'p FdPool,
+    path:   OsString,
+    file:   Option<Arc<File>>,
+    data:   Option<Arc<Vec<u8>>>, // content that is only in memory, instead of the file
+    offset: u64,
+    pos:    u64,
+    size:   u64,
+}
+
+impl<'p> RangeReader<'p> {
+    pub fn new(pool: &'p FdPool, path: OsString, offset: u64, size: u64) -> RangeReader<'p> {
+        RangeReader{
+            pool:   pool,
+            path:   path,
+            file:   None,
+            data:   None,
+            offset: offset,
+            pos:    0,
+            size:   size,
+        }
+    }
+
+    pub fn memory(data: Arc<Vec<u8>>, offset: u64, size: u64) -> RangeReader<'static> {
+        RangeReader{
+            pool:   &POOL,
+            path:   OsString::new(),
+            file:   None,
+            data:   Some(data),
+            offset: offset,
+            pos:    0,
+            size:   size,
+        }
+    }
+}
+
+impl<'p> Read for RangeReader<'p> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.size || buf.len() < 1 {
+            return Ok(0);
+        }
+        if let Some(ref data) = self.data {
+            let from = ::std::cmp::min((self.offset + self.pos) as usize, data.len());
+            let n = ::std::cmp::min(::std::cmp::min(buf.len() as u64, self.size - self.pos) as usize, data.len() - from);
+            buf[..n].copy_from_slice(&data[from..from + n]);
+            self.pos += n as u64;
+            return Ok(n);
+        }
+        if self.file.is_none() {
+            self.file = Some(self.pool.open(&self.path)?);
+        }
+        let max = ::std::cmp::min(buf.len() as u64, self.size - self.pos) as usize;
+        let n = self.file.as_ref().unwrap().read_at(&mut buf[..max], self.offset + self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// only relative forward seeks, returning how far it moved, like readchain::Chain expects
+impl<'p> Seek for RangeReader<'p> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Current(n) if n >= 0 => {
+                let n = ::std::cmp::min(n as u64, self.size - self.pos);
+                self.pos += n;
+                Ok(n)
+            },
+            _ => Err(io::Error::new(ErrorKind::InvalidInput, "can only seek forward on RangeReader")),
+        }
+    }
+}
+
+
+#[test]
+fn bounded_pool() {
+    let pool = new(2);
+    let a = OsString::from("test/readchain/a");
+    let b = OsString::from("test/readchain/b");
+    let c = OsString::from("Cargo.toml");
+
+    pool.open(&a).unwrap();
+    let fb = pool.open(&b).unwrap();
+    pool.open(&a).unwrap();
+    assert_eq!(pool.opens(), 2);
+
+    // b is evicted, but a handed out descriptor keeps working
+    pool.open(&c).unwrap();
+    assert_eq!(pool.len(), 2);
+    pool.open(&a).unwrap();
+    assert_eq!(pool.opens(), 3);
+    let mut buf = [0; 2];
+    assert_eq!(fb.read_at(&mut buf, 2).unwrap(), 2);
+    assert_eq!(&buf, b"ol");
+    pool.open(&b).unwrap();
+    assert_eq!(pool.opens(), 4);
+
+    // a smaller bound only applies on the next open
+    pool.set_capacity(1);
+    pool.open(&c).unwrap();
+    assert_eq!(pool.len(), 1);
+    pool.set_capacity(2);
+
+    // readers of the same file don't disturb each other
+    let mut r1 = RangeReader::new(&pool, b.clone(), 5, 5);
+    let mut r2 = RangeReader::new(&pool, b.clone(), 0, 4);
+    let mut s1 = [0; 3];
+    let mut s2 = String::new();
+    r1.read_exact(&mut s1).unwrap();
+    r2.read_to_string(&mut s2).unwrap();
+    assert_eq!(&s1, b"stu");
+    assert_eq!(s2, "cool");
+    assert_eq!(r1.seek(SeekFrom::Current(10)).unwrap(), 2);
+    assert_eq!(r1.read(&mut s1).unwrap(), 0);
+}
@@ -0,0 +1,236 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// gitignore style include/exclude rules for building an index from the host
+pub struct Filter {
+    rules: Vec<Rule>,
+    pub one_file_system: bool, // don't descend into directories on other devices (like -x)
+}
+
+struct Rule {
+    pattern:  Vec<u8>,
+    include:  bool, // '!' prefix, re-includes what an earlier rule excluded
+    dir_only: bool, // trailing '/', only matches directories
+    anchored: bool, // contains a '/', matches the full path instead of the name
+}
+
+impl Filter {
+    pub fn new() -> Filter {
+        Filter {
+            rules: Vec::new(),
+            one_file_system: false,
+        }
+    }
+
+    pub fn exclude<S: AsRef<OsStr>>(&mut self, pattern: S) {
+        self.add_rule(pattern.as_ref().as_bytes(), false);
+    }
+
+    pub fn include<S: AsRef<OsStr>>(&mut self, pattern: S) {
+        self.add_rule(pattern.as_ref().as_bytes(), true);
+    }
+
+    /// add a single line in gitignore syntax. empty lines and lines starting with '#' are ignored,
+    /// a leading '!' turns the pattern into an include
+    pub fn add_line(&mut self, line: &[u8]) {
+        let mut line = line;
+        while let Some((&c, rest)) = line.split_last() {
+            if c != b'\n' && c != b'\r' && c != b' ' {
+                break;
+            }
+            line = rest;
+        }
+        if line.is_empty() || line[0] == b'#' {
+            return;
+        }
+        if line[0] == b'!' {
+            self.add_rule(&line[1..], true);
+        } else {
+            self.add_rule(line, false);
+        }
+    }
+
+    /// read a filter list, one gitignore style pattern per line
+    pub fn add_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let mut f = BufReader::new(File::open(path)?);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if f.read_until(b'\n', &mut line)? < 1 {
+                return Ok(());
+            }
+            self.add_line(&line);
+        }
+    }
+
+    fn add_rule(&mut self, pattern: &[u8], include: bool) {
+        let mut pattern = pattern;
+        let mut dir_only = false;
+        if pattern.len() > 1 && pattern[pattern.len()-1] == b'/' {
+            dir_only = true;
+            pattern = &pattern[..pattern.len()-1];
+        }
+        let anchored = pattern.contains(&b'/');
+        if pattern.first() == Some(&b'/') {
+            pattern = &pattern[1..];
+        }
+        if pattern.is_empty() {
+            return;
+        }
+        self.rules.push(Rule{
+            pattern:  pattern.to_vec(),
+            include:  include,
+            dir_only: dir_only,
+            anchored: anchored,
+        });
+    }
+
+    /// path is relative to the root of the walk, separated by '/'. the last matching rule wins.
+    pub fn is_excluded(&self, path: &[u8], is_dir: bool) -> bool {
+        let name = match path.iter().rposition(|&c| c == b'/') {
+            Some(p) => &path[p+1..],
+            None    => path,
+        };
+        for rule in self.rules.iter().rev() {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            let subject = if rule.anchored { path } else { name };
+            if glob(&rule.pattern, subject) {
+                return !rule.include;
+            }
+        }
+        false
+    }
+}
+
+/// shell glob with '**' crossing directory boundaries.
+fn glob(p: &[u8], s: &[u8]) -> bool {
+    if p.is_empty() {
+        return s.is_empty();
+    }
+
+    if p.starts_with(b"**") {
+        let rest = &p[2..];
+        // "**/" matches zero or more directories
+        if rest.first() == Some(&b'/') {
+            let rest = &rest[1..];
+            if glob(rest, s) {
+                return true;
+            }
+            return s.iter().enumerate().any(|(i, &c)| c == b'/' && glob(rest, &s[i+1..]));
+        }
+        return (0..s.len()+1).any(|i| glob(rest, &s[i..]));
+    }
+
+    match p[0] {
+        b'*' => {
+            for i in 0..s.len()+1 {
+                if glob(&p[1..], &s[i..]) {
+                    return true;
+                }
+                if i < s.len() && s[i] == b'/' {
+                    break;
+                }
+            }
+            false
+        },
+        b'?' => {
+            !s.is_empty() && s[0] != b'/' && glob(&p[1..], &s[1..])
+        },
+        b'[' => {
+            match class(&p[1..], s.first()) {
+                None => !s.is_empty() && s[0] == b'[' && glob(&p[1..], &s[1..]),
+                Some((matched, len)) => matched && glob(&p[1+len..], &s[1..]),
+            }
+        },
+        b'\\' if p.len() > 1 => {
+            !s.is_empty() && s[0] == p[1] && glob(&p[2..], &s[1..])
+        },
+        c => {
+            !s.is_empty() && s[0] == c && glob(&p[1..], &s[1..])
+        },
+    }
+}
+
+/// match a [...] character class. p starts after the '['.
+/// returns if c matched and how many bytes of p the class used, or None if the class isn't closed.
+fn class(p: &[u8], c: Option<&u8>) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negate = match p.first() {
+        Some(&b'!') | Some(&b'^') => { i += 1; true },
+        _ => false,
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        if i >= p.len() {
+            return None;
+        }
+        if p[i] == b']' && !first {
+            break;
+        }
+        first = false;
+        let lo = p[i];
+        let hi = if i + 2 < p.len() && p[i+1] == b'-' && p[i+2] != b']' {
+            i += 2;
+            p[i]
+        } else {
+            lo
+        };
+        if let Some(&c) = c {
+            if c >= lo && c <= hi {
+                matched = true;
+            }
+        }
+        i += 1;
+    }
+    match c {
+        None | Some(&b'/') => Some((false, i + 1)),
+        Some(_) => Some((matched != negate, i + 1)),
+    }
+}
+
+
+#[test]
+fn glob_basics() {
+    assert!(glob(b"*.o", b"main.o"));
+    assert!(!glob(b"*.o", b"main.oo"));
+    assert!(!glob(b"*.o", b"src/main.o"));
+    assert!(glob(b"ma?n.[oa]", b"main.a"));
+    assert!(!glob(b"ma?n.[!oa]", b"main.a"));
+    assert!(glob(b"[a-c]x", b"bx"));
+    assert!(glob(b"[]x", b"[]x"));
+    assert!(glob(b"\\*", b"*"));
+    assert!(!glob(b"\\*", b"a"));
+    assert!(glob(b"**/foo", b"foo"));
+    assert!(glob(b"**/foo", b"a/b/foo"));
+    assert!(glob(b"a/**/b", b"a/b"));
+    assert!(glob(b"a/**/b", b"a/x/y/b"));
+    assert!(glob(b"a/**", b"a/x/y"));
+    assert!(!glob(b"a/**", b"a"));
+}
+
+#[test]
+fn filter_rules() {
+    let mut f = Filter::new();
+    f.add_line(b"# comment\n");
+    f.add_line(b"*.jpg\n");
+    f.add_line(b"!cat.jpg\n");
+    f.add_line(b"/target/");
+    f.add_line(b"build/out\n");
+    f.add_line(b"");
+
+    assert!(f.is_excluded(b"image_same/cat2.jpg", false));
+    assert!(!f.is_excluded(b"image_same/cat.jpg", false));
+    assert!(f.is_excluded(b"target", true));
+    assert!(!f.is_excluded(b"target", false));
+    assert!(!f.is_excluded(b"src/target", true));
+    assert!(f.is_excluded(b"build/out", false));
+    assert!(!f.is_excluded(b"x/build/out", false));
+    assert!(!f.is_excluded(b"# comment", false));
+    assert!(!f.is_excluded(b"mep", false));
+}
@@ -1,12 +1,31 @@
 use blockstore::{BlockStore};
+use cache::{self, BlockCache};
+use crypto::{self, Decrypt};
+use export::lchown;
 use fuse::*;
 use index::{Index, Inode};
-use libc::ENOENT;
-use readchain::{Take,Chain};
-use std::collections::HashMap;
-use std::ffi::OsStr;
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use handles::{self, Handles};
+use inodes::{self, InodeMap};
+use metrics::{self, Metrics};
+use libc::{c_int, ENOENT, EROFS, EEXIST, EISDIR, ENOTDIR, ENOTEMPTY, EXDEV, EPERM, EBADF, EACCES, EINVAL,
+           R_OK, W_OK, X_OK,
+           O_ACCMODE, O_RDONLY, O_WRONLY, O_TRUNC, O_EXCL};
+use overlay::{self, OPAQUE};
+use workers::{self, Pool};
+use readchain::{Take,Chain,Fallible};
+use fdpool::RangeReader;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Debug;
+use std::fs::{self, File, Metadata, OpenOptions, Permissions, DirBuilder};
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Instant;
 use time::Timespec;
 use std::boxed::Box;
 
@@ -14,9 +33,12 @@ const TTL: Timespec = Timespec { sec: 1, nsec: 0 };                 // 1 second
 
 const CREATE_TIME: Timespec = Timespec { sec: 1381237736, nsec: 0 };    // 2013-10-08 08:56
 
+const BLOCK_SIZE: u32 = 4096;   // unit of the sizes reported by statfs
+const NAME_MAX: u32 = 255;
+
 fn entry_to_file_attr(entry: &Inode) -> FileAttr{
     FileAttr {
-        ino: entry.i + 1,
+        ino: inodes::from_index(entry.i),
         size: entry.s,
         blocks: entry.s * 512,
         atime: CREATE_TIME,
@@ -25,6 +47,7 @@ fn entry_to_file_attr(entry: &Inode) -> FileAttr{
         crtime: CREATE_TIME,
         kind: match entry.k {
             1 => FileType::Directory,
+            3 => FileType::Symlink,
             _ => FileType::RegularFile,
         },
         perm: entry.a,
@@ -32,18 +55,70 @@ fn entry_to_file_attr(entry: &Inode) -> FileAttr{
             Some(ref d) => d.len() + 1,
             _ => 1,
         } as u32,
-        uid: 1000,
-        gid: 1000,
+        uid: entry.u,
+        gid: entry.g,
         rdev: 0,
         flags: 0,
     }
 }
 
 
+enum Node<'a> {
+    Lower(&'a Inode),
+    Upper(Metadata),
+}
+
+struct OpenFile {
+    inode: u64,
+    next:  AtomicU64, // where the last read ended, to detect sequential access
+    upper: Option<File>, // the file in the upper directory, if it was copied up
+}
+
 pub struct Fuse<'a> {
     index:      &'a Index,
     blockstore: &'a BlockStore,
-    open_files:  HashMap<u64, Box<Read + 'a>>,
+    open_files:  Handles<OpenFile>,
+    open_dirs:   Handles<Vec<(OsString, u64, FileType)>>, // listing at opendir, so offsets stay valid
+    usage:       Usage,
+
+    pub cache: BlockCache,
+    pub metrics: Metrics,
+
+    // changes go to this directory, the index stays read only. without it, the mount is read only.
+    pub upper: Option<PathBuf>,
+    // held for writing while the upper directory changes, so lookups never see half a change
+    changes:    RwLock<()>,
+    inodes:     Mutex<InodeMap>,
+}
+
+/// what statfs reports, the index never changes while mounted
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Usage {
+    size:   u64, // bytes of file content
+    blocks: u64, // size in BLOCK_SIZE units, each file rounded up
+    inodes: u64,
+}
+
+fn usage(index: &Index) -> Usage {
+    let mut u = Usage{size: 0, blocks: 0, inodes: index.inodes.len() as u64};
+    for inode in index.inodes.iter().filter(|i| i.k == 2) {
+        u.size += inode.s;
+        u.blocks += (inode.s + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64;
+    }
+    u
+}
+
+fn read_upper(f: &File, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; size as usize];
+    let mut n = 0;
+    while n < buf.len() {
+        match f.read_at(&mut buf[n..], offset + n as u64)? {
+            0 => break,
+            r => n += r,
+        }
+    }
+    buf.truncate(n);
+    Ok(buf)
 }
 
 impl<'a> Fuse<'a> {
@@ -51,116 +126,1141 @@ impl<'a> Fuse<'a> {
         Fuse{
             index: index,
             blockstore: blockstore,
-            open_files: HashMap::new(),
+            open_files: handles::new(handles::DEFAULT_CAPACITY),
+            open_dirs: handles::new(handles::DEFAULT_CAPACITY),
+            usage: usage(index),
+
+            cache: cache::new(cache::DEFAULT_BUDGET),
+            metrics: metrics::new(false),
+
+            upper: None,
+            changes: RwLock::new(()),
+            inodes: Mutex::new(inodes::new(index.inodes.len())),
         }
     }
-}
 
-impl<'a>  Filesystem for Fuse<'a> {
-    fn lookup (&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+    /// run a request of op, counting it and how long it took
+    fn timed<T, E: Debug, F: FnOnce() -> Result<T, E>>(&self, op: &'static str, f: F) -> Result<T, E> {
+        let start = if self.metrics.latency { Some(Instant::now()) } else { None };
+        let r = f();
+        self.metrics.record(op, start.map(|s| s.elapsed()), r.is_ok());
+        if let Err(ref e) = r {
+            debug!("op={} error={:?}", op, e);
+        }
+        r
+    }
 
-        let mb = self.index.inodes.get((parent - 1) as usize)
-            .and_then(|entry| entry.d.as_ref())
-            .and_then(|d| d.get(&name.to_string_lossy().into_owned()))
-            .and_then(|e| self.index.inodes.get(e.i as usize));
+    pub fn dump_stats(&self) {
+        for line in self.metrics.report(&self.cache.stats()) {
+            info!("{}", line);
+        }
+    }
 
-        match mb {
-            None => reply.error(ENOENT),
-            Some(entry) => {
-                let fa = &entry_to_file_attr(entry);
-                reply.entry(&TTL, fa, 0)
+    /// up to size bytes of inode at offset, going through the block cache.
+    /// for sequential reads, also the block after the last one read, to load it ahead.
+    fn read_at(&self, inode: &Inode, offset: u64, size: u32, sequential: bool) -> io::Result<(Vec<u8>, Option<String>)> {
+        let mut buf = Vec::with_capacity(size as usize);
+        let end = offset + size as u64;
+        let mut pos = 0;
+        let mut next = None;
+        if let Some(ref c) = inode.c {
+            for (n, e) in c.iter().enumerate() {
+                if pos >= end {
+                    next = Some(n);
+                    break;
+                }
+                if pos + e.l > offset {
+                    let data = self.cache.get(self.blockstore, &e.h)?;
+                    let from = e.o + offset.saturating_sub(pos);
+                    let to   = e.o + ::std::cmp::min(e.l, end - pos);
+                    let start = buf.len();
+                    buf.extend_from_slice(&data[from as usize..to as usize]);
+                    if let Some(key) = e.key()? {
+                        crypto::decrypt_block_at(&key, from, &mut buf[start..]);
+                    }
+                }
+                pos += e.l;
+            }
+            if sequential {
+                return Ok((buf, next.map(|n| c[n].h.clone())));
             }
         }
+        Ok((buf, None))
     }
 
-    fn getattr (&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        println!("getattr {:?}", ino);
+    /// load a block into the cache before it is read. only a guess, so failing is fine.
+    fn readahead(&self, hash: &String) {
+        if let Err(e) = self.cache.readahead(self.blockstore, hash) {
+            warn!("readahead of block {} failed: {}", hash, e);
+        }
+    }
+
+    fn lower(&self, path: &OsStr) -> Option<&'a Inode> {
+        let index = self.index;
+        let mut cur = &index.inodes[0];
+        for name in overlay::names(path) {
+            let e = cur.d.as_ref().and_then(|d| d.get(name))?;
+            cur = &index.inodes[e.i as usize];
+        }
+        Some(cur)
+    }
+
+    /// the index node at path, unless it was deleted in the upper directory
+    fn visible_lower(&self, path: &OsStr) -> Option<&'a Inode> {
+        if let Some(ref upper) = self.upper {
+            if overlay::lower_hidden(upper, path) {
+                return None;
+            }
+        }
+        self.lower(path)
+    }
+
+    fn resolve(&self, path: &OsStr) -> Option<Node<'a>> {
+        if let Some(ref upper) = self.upper {
+            if let Ok(m) = fs::symlink_metadata(upper.join(path)) {
+                return Some(Node::Upper(m));
+            }
+        }
+        self.visible_lower(path).map(Node::Lower)
+    }
+
+    /// index nodes keep their inode number when they are copied up
+    fn ino(&self, path: &OsStr) -> u64 {
+        let lower = self.visible_lower(path).map(|inode| inode.i);
+        self.inodes.lock().unwrap().get(path, lower)
+    }
+
+    /// attributes of path for a reply the kernel keeps a lookup for
+    fn entry(&self, path: &OsStr) -> Result<FileAttr, c_int> {
+        let attr = self.attr(path)?;
+        self.inodes.lock().unwrap().lookup(attr.ino);
+        Ok(attr)
+    }
+
+    fn path(&self, ino: u64) -> Result<OsString, c_int> {
+        self.inodes.lock().unwrap().path(ino).cloned().ok_or(ENOENT)
+    }
+
+    fn child(&self, parent: u64, name: &OsStr) -> Result<OsString, c_int> {
+        Ok(overlay::join(&self.path(parent)?, name))
+    }
+
+    fn attr(&self, path: &OsStr) -> Result<FileAttr, c_int> {
+        match self.resolve(path) {
+            None => Err(ENOENT),
+            Some(Node::Lower(inode)) => {
+                let mut a = entry_to_file_attr(inode);
+                a.ino = self.ino(path);
+                Ok(a)
+            },
+            Some(Node::Upper(m)) => {
+                let ino = self.ino(path);
+                Ok(overlay::meta_to_file_attr(ino, &m))
+            },
+        }
+    }
+
+    fn upper(&self) -> Result<PathBuf, c_int> {
+        self.upper.clone().ok_or(EROFS)
+    }
+
+    /// make sure path and its parents exist in the upper directory
+    fn copy_up(&self, path: &OsStr) -> Result<(), c_int> {
+        let upper = self.upper()?;
+        if path.is_empty() || fs::symlink_metadata(upper.join(path)).is_ok() {
+            return Ok(());
+        }
+        self.copy_up(overlay::split(path).0)?;
+        let inode = self.visible_lower(path).ok_or(ENOENT)?;
+        overlay::copy_up(inode, self.blockstore, &upper.join(path)).map_err(overlay::errno)
+    }
+
+    /// hide the index node at path
+    fn whiteout(&self, path: &OsStr) -> Result<(), c_int> {
+        if self.visible_lower(path).is_none() {
+            return Ok(());
+        }
+        self.copy_up(overlay::split(path).0)?;
+        File::create(overlay::whiteout_path(&self.upper()?, path)).map_err(overlay::errno)?;
+        Ok(())
+    }
+
+    fn clear_whiteout(&self, path: &OsStr) -> Result<(), c_int> {
+        fs::remove_file(overlay::whiteout_path(&self.upper()?, path)).or_else(|e| {
+            if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(overlay::errno(e)) }
+        })
+    }
+
+    fn is_dir(&self, path: &OsStr) -> Result<bool, c_int> {
+        match self.resolve(path) {
+            None => Err(ENOENT),
+            Some(Node::Lower(inode)) => Ok(inode.k == 1),
+            Some(Node::Upper(m)) => Ok(m.is_dir()),
+        }
+    }
+
+    /// merged directory listing of the upper directory and the index, sorted by name
+    fn entries(&self, path: &OsStr) -> Result<Vec<(OsString, u64, FileType)>, c_int> {
+        let mut names = BTreeMap::new();
+        let upper_dir = match self.resolve(path) {
+            None => return Err(ENOENT),
+            Some(Node::Lower(inode)) if inode.k != 1 => return Err(ENOTDIR),
+            Some(Node::Upper(ref m)) if !m.is_dir() => return Err(ENOTDIR),
+            Some(Node::Upper(_)) => self.upper.as_ref().map(|u| u.join(path)),
+            Some(Node::Lower(_)) => None,
+        };
+
+        let opaque = upper_dir.as_ref().map(|d| d.join(OPAQUE).exists()).unwrap_or(false);
+        if let Some(inode) = self.visible_lower(path) {
+            if let (false, Some(ref d)) = (opaque, inode.d.as_ref()) {
+                for (name, e) in d.iter() {
+                    let hidden = self.upper.as_ref().map(|u| overlay::is_whiteout(u, &overlay::join(path, name))).unwrap_or(false);
+                    if !hidden {
+                        names.insert(name.clone(), match e.k {
+                            1 => FileType::Directory,
+                            3 => FileType::Symlink,
+                            _ => FileType::RegularFile,
+                        });
+                    }
+                }
+            }
+        }
+        if let Some(dir) = upper_dir {
+            for e in fs::read_dir(dir).map_err(overlay::errno)? {
+                let e = e.map_err(overlay::errno)?;
+                let name = e.file_name();
+                if overlay::is_reserved(&name) {
+                    continue;
+                }
+                let ft = e.file_type().map_err(overlay::errno)?;
+                names.insert(name, if ft.is_dir() {
+                    FileType::Directory
+                } else if ft.is_symlink() {
+                    FileType::Symlink
+                } else {
+                    FileType::RegularFile
+                });
+            }
+        }
+
+        Ok(names.into_iter().map(|(name, kind)| {
+            let ino = self.ino(&overlay::join(path, &name));
+            (name, ino, kind)
+        }).collect())
+    }
+
+    /// all entries of a directory including . and .., in a stable order
+    fn listing(&self, ino: u64) -> Result<Vec<(OsString, u64, FileType)>, c_int> {
+        let path = self.path(ino)?;
+        let parent = match self.resolve(&path) {
+            _ if path.is_empty() => inodes::ROOT,
+            Some(Node::Lower(inode)) => inodes::from_index(inode.p),
+            _ => self.ino(overlay::split(&path).0),
+        };
+        let mut r = vec![
+            (OsString::from("."),  ino,    FileType::Directory),
+            (OsString::from(".."), parent, FileType::Directory),
+        ];
+        r.extend(self.entries(&path)?);
+        Ok(r)
+    }
+
+    fn do_opendir(&self, ino: u64) -> Result<u64, c_int> {
+        let entries = {
+            let _r = self.changes.read().unwrap();
+            self.listing(ino)?
+        };
+        self.open_dirs.insert(entries)
+    }
+
+    /// entries of an open directory, or the current ones if it wasn't opened
+    fn do_readdir(&self, ino: u64, fh: u64) -> Result<Arc<Vec<(OsString, u64, FileType)>>, c_int> {
+        if let Ok(entries) = self.open_dirs.get(fh) {
+            return Ok(entries);
+        }
+        let _r = self.changes.read().unwrap();
+        self.listing(ino).map(Arc::new)
+    }
 
-        match self.index.inodes.get((ino - 1) as usize) {
-            None => reply.error(ENOENT),
-            Some(entry) => {
-                reply.attr(&TTL, &entry_to_file_attr(entry));
+    fn do_getattr(&self, ino: u64) -> Result<FileAttr, c_int> {
+        let _r = self.changes.read().unwrap();
+        self.path(ino).and_then(|path| self.attr(&path))
+    }
+
+    /// like access(2) with the mode bits of the node. only the primary group of the caller is known.
+    fn do_access(&self, ino: u64, uid: u32, gid: u32, mask: u32) -> Result<(), c_int> {
+        let attr = self.do_getattr(ino)?;
+        let mask = mask as c_int;
+        if mask & W_OK != 0 && self.upper.is_none() {
+            return Err(EROFS);
+        }
+        let perm = attr.perm as c_int;
+        let allowed = if uid == 0 {
+            // root may do anything but execute files nobody may execute
+            let x = if attr.kind == FileType::Directory || perm & 0o111 != 0 { X_OK } else { 0 };
+            R_OK | W_OK | x
+        } else if uid == attr.uid {
+            perm >> 6 & 7
+        } else if gid == attr.gid {
+            perm >> 3 & 7
+        } else {
+            perm & 7
+        };
+        if mask & !allowed & (R_OK | W_OK | X_OK) != 0 {
+            return Err(EACCES);
+        }
+        Ok(())
+    }
+
+    fn do_readlink(&self, ino: u64) -> Result<Vec<u8>, c_int> {
+        let _r = self.changes.read().unwrap();
+        let path = self.path(ino)?;
+        match self.resolve(&path) {
+            None => Err(ENOENT),
+            Some(Node::Lower(inode)) => match (inode.k, inode.l.as_ref()) {
+                (3, Some(l)) => Ok(l.as_bytes().to_vec()),
+                (3, None) => Ok(Vec::new()),
+                _ => Err(EINVAL),
+            },
+            Some(Node::Upper(ref m)) if m.file_type().is_symlink() => {
+                let target = fs::read_link(self.upper()?.join(&path)).map_err(overlay::errno)?;
+                Ok(target.as_os_str().as_bytes().to_vec())
+            },
+            Some(Node::Upper(_)) => Err(EINVAL),
+        }
+    }
+
+    pub fn do_lookup(&self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        if overlay::is_reserved(name) {
+            return Err(ENOENT);
+        }
+        let _r = self.changes.read().unwrap();
+        let path = self.child(parent, name)?;
+        self.entry(&path)
+    }
+
+    fn do_setattr(&self, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>,
+                  size: Option<u64>) -> Result<FileAttr, c_int> {
+        if mode.is_none() && uid.is_none() && gid.is_none() && size.is_none() {
+            return self.do_getattr(ino);
+        }
+        let _w = self.changes.write().unwrap();
+        let path = self.path(ino)?;
+        self.copy_up(&path)?;
+        let target = self.upper()?.join(&path);
+        let m = fs::symlink_metadata(&target).map_err(overlay::errno)?;
+        if uid.is_some() || gid.is_some() {
+            lchown(&target, uid.unwrap_or(m.uid()), gid.unwrap_or(m.gid())).map_err(overlay::errno)?;
+        }
+        if let (Some(mode), false) = (mode, m.file_type().is_symlink()) {
+            fs::set_permissions(&target, Permissions::from_mode(mode & 0o7777)).map_err(overlay::errno)?;
+        }
+        if let Some(size) = size {
+            OpenOptions::new().write(true).open(&target).and_then(|f| f.set_len(size)).map_err(overlay::errno)?;
+        }
+        self.attr(&path)
+    }
+
+    fn do_open(&self, ino: u64, flags: u32) -> Result<u64, c_int> {
+        let flags = flags as c_int;
+        let acc = flags & O_ACCMODE;
+        let write = acc != O_RDONLY || flags & O_TRUNC != 0;
+        let (_r, _w);
+        let path;
+        if write {
+            _w = self.changes.write().unwrap();
+            path = self.path(ino)?;
+            self.copy_up(&path)?;
+        } else {
+            _r = self.changes.read().unwrap();
+            path = self.path(ino)?;
+        }
+        let file = match self.resolve(&path) {
+            None => return Err(ENOENT),
+            Some(Node::Upper(_)) => {
+                let f = OpenOptions::new()
+                    .read(acc != O_WRONLY)
+                    .write(write)
+                    .truncate(flags & O_TRUNC != 0)
+                    .open(self.upper()?.join(&path))
+                    .map_err(overlay::errno)?;
+                OpenFile{
+                    inode: 0,
+                    next:  AtomicU64::new(0),
+                    upper: Some(f),
+                }
+            },
+            Some(Node::Lower(inode)) => OpenFile{
+                inode: inode.i,
+                next:  AtomicU64::new(0),
+                upper: None,
+            },
+        };
+        self.open_files.insert(file)
+    }
+
+    /// the data and the block to load ahead after replying
+    fn do_read(&self, fh: u64, offset: u64, size: u32) -> Result<(Vec<u8>, Option<String>), c_int> {
+        let file = self.open_files.get(fh)?;
+        let r = match file.upper {
+            Some(ref f) => read_upper(f, offset, size).map(|buf| (buf, None)),
+            None => {
+                let sequential = file.next.swap(offset + size as u64, Ordering::Relaxed) == offset;
+                let index = self.index;
+                self.read_at(&index.inodes[file.inode as usize], offset, size, sequential)
+            },
+        };
+        r.map_err(|e| {
+            warn!("read fh={} offset={} failed: {}", fh, offset, e);
+            overlay::errno(e)
+        })
+    }
+
+    pub fn do_write(&self, fh: u64, offset: u64, data: &[u8]) -> Result<u32, c_int> {
+        let file = self.open_files.get(fh)?;
+        match file.upper.as_ref() {
+            None => Err(EBADF),
+            Some(f) => {
+                let mut n = 0;
+                while n < data.len() {
+                    n += f.write_at(&data[n..], offset + n as u64).map_err(overlay::errno)?;
+                }
+                Ok(n as u32)
+            },
+        }
+    }
+
+    pub fn do_create(&self, parent: u64, name: &OsStr, mode: u32, flags: u32) -> Result<(FileAttr, u64), c_int> {
+        let _w = self.changes.write().unwrap();
+        if overlay::is_reserved(name) {
+            return Err(EPERM);
+        }
+        let path = self.child(parent, name)?;
+        let upper = self.upper()?;
+        let flags = flags as c_int;
+        if self.visible_lower(&path).is_some() {
+            if flags & O_EXCL != 0 {
+                return Err(EEXIST);
             }
+            self.copy_up(&path)?;
+        } else {
+            self.copy_up(overlay::split(&path).0)?;
+            self.clear_whiteout(&path)?;
+        }
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .create_new(flags & O_EXCL != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .mode(mode & 0o7777)
+            .open(upper.join(&path))
+            .map_err(overlay::errno)?;
+        let fh = self.open_files.insert(OpenFile{
+            inode: 0,
+            next:  AtomicU64::new(0),
+            upper: Some(f),
+        })?;
+        Ok((self.entry(&path)?, fh))
+    }
+
+    fn do_mkdir(&self, parent: u64, name: &OsStr, mode: u32) -> Result<FileAttr, c_int> {
+        let _w = self.changes.write().unwrap();
+        if overlay::is_reserved(name) {
+            return Err(EPERM);
+        }
+        let path = self.child(parent, name)?;
+        let upper = self.upper()?;
+        if self.resolve(&path).is_some() {
+            return Err(EEXIST);
+        }
+        self.copy_up(overlay::split(&path).0)?;
+        self.clear_whiteout(&path)?;
+        DirBuilder::new().mode(mode & 0o7777).create(upper.join(&path)).map_err(overlay::errno)?;
+
+        // a deleted index directory must not show its old content again
+        if self.lower(&path).map(|i| i.k == 1).unwrap_or(false) {
+            File::create(upper.join(&path).join(OPAQUE)).map_err(overlay::errno)?;
+        }
+        self.entry(&path)
+    }
+
+    pub fn do_unlink(&self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        let _w = self.changes.write().unwrap();
+        let path = self.child(parent, name)?;
+        let upper = self.upper()?;
+        if self.is_dir(&path)? {
+            return Err(EISDIR);
+        }
+        if fs::symlink_metadata(upper.join(&path)).is_ok() {
+            fs::remove_file(upper.join(&path)).map_err(overlay::errno)?;
+        }
+        self.whiteout(&path)?;
+        self.inodes.lock().unwrap().remove(&path);
+        Ok(())
+    }
+
+    fn do_rmdir(&self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        let _w = self.changes.write().unwrap();
+        let path = self.child(parent, name)?;
+        let upper = self.upper()?;
+        if !self.is_dir(&path)? {
+            return Err(ENOTDIR);
+        }
+        if !self.entries(&path)?.is_empty() {
+            return Err(ENOTEMPTY);
+        }
+        // only whiteouts are left in it
+        if upper.join(&path).exists() {
+            fs::remove_dir_all(upper.join(&path)).map_err(overlay::errno)?;
         }
+        self.whiteout(&path)?;
+        self.inodes.lock().unwrap().remove(&path);
+        Ok(())
     }
 
+    fn do_rename(&self, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr) -> Result<(), c_int> {
+        let _w = self.changes.write().unwrap();
+        if overlay::is_reserved(newname) {
+            return Err(EPERM);
+        }
+        let src = self.child(parent, name)?;
+        let dst = self.child(newparent, newname)?;
+        let upper = self.upper()?;
 
-    fn open(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
-        println!("open {:?}", ino);
-        match self.index.inodes.get((ino - 1) as usize) {
-            None => {reply.error(ENOENT);},
-            Some(entry) => {
-                let mut fh = entry.i;
-                while self.open_files.contains_key(&fh) {
-                    fh += 1;
+        let src_dir = self.is_dir(&src)?;
+        // like overlayfs without redirect_dir, index directories can't be moved.
+        // mv falls back to copying on EXDEV.
+        if src_dir && self.visible_lower(&src).is_some() {
+            return Err(EXDEV);
+        }
+        let dst_lower_dir = match self.resolve(&dst) {
+            None => false,
+            Some(_) => {
+                let dst_dir = self.is_dir(&dst)?;
+                if dst_dir != src_dir {
+                    return Err(if dst_dir { EISDIR } else { ENOTDIR });
+                }
+                if dst_dir {
+                    if !self.entries(&dst)?.is_empty() {
+                        return Err(ENOTEMPTY);
+                    }
+                    if upper.join(&dst).exists() {
+                        fs::remove_dir_all(upper.join(&dst)).map_err(overlay::errno)?;
+                    }
                 }
-                self.open_files.insert(fh, Box::new(entry.chain(self.blockstore)));
-                reply.opened(fh, 0);
+                dst_dir && self.visible_lower(&dst).is_some()
             },
         };
+
+        let ino = self.ino(&src);
+        self.copy_up(&src)?;
+        self.copy_up(overlay::split(&dst).0)?;
+        self.clear_whiteout(&dst)?;
+        fs::rename(upper.join(&src), upper.join(&dst)).map_err(overlay::errno)?;
+        self.whiteout(&src)?;
+        if dst_lower_dir {
+            File::create(upper.join(&dst).join(OPAQUE)).map_err(overlay::errno)?;
+        }
+
+        // the kernel keeps the inode, so its path moves along with everything below it
+        self.inodes.lock().unwrap().rename(&src, &dst, ino);
+        Ok(())
+    }
+}
+
+/// the filesystem as the kernel sees it. requests are answered by a pool of worker threads,
+/// so a slow block fetch only holds up the requests waiting for it.
+pub struct Session<'s, 'a: 's> {
+    fs:   &'s Fuse<'a>,
+    pool: Pool<'s>,
+}
+
+/// serve fs at mountpoint with the given number of worker threads until it is unmounted.
+/// sending the process SIGUSR1 logs the request and cache statistics.
+pub fn mount<P: AsRef<Path>>(fs: &Fuse, threads: usize, mountpoint: &P, options: &[&OsStr]) -> io::Result<()> {
+    metrics::block_signal();
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        s.spawn(|| metrics::on_signal(&done, || fs.dump_stats()));
+        let r = workers::scope(threads, |pool| fuse::mount(Session{fs: fs, pool: pool}, mountpoint, options));
+        done.store(true, Ordering::SeqCst);
+        r
+    })
+}
+
+impl<'s, 'a: 's> Filesystem for Session<'s, 'a> {
+    fn lookup (&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let (fs, name) = (self.fs, name.to_os_string());
+        self.pool.execute(move || match fs.timed("lookup", || fs.do_lookup(parent, &name)) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        });
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        self.fs.inodes.lock().unwrap().forget(ino, nlookup);
+    }
+
+    fn getattr (&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        trace!("getattr ino={}", ino);
+        let fs = self.fs;
+        self.pool.execute(move || match fs.timed("getattr", || fs.do_getattr(ino)) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        });
+    }
+
+    fn setattr(&mut self, _req: &Request, ino: u64, mode: Option<u32>, uid: Option<u32>, gid: Option<u32>,
+               size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, _fh: Option<u64>,
+               _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>,
+               _flags: Option<u32>, reply: ReplyAttr) {
+        let fs = self.fs;
+        self.pool.execute(move || match fs.timed("setattr", || fs.do_setattr(ino, mode, uid, gid, size)) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        });
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        trace!("open ino={} flags={:#o}", ino, flags);
+        let fs = self.fs;
+        self.pool.execute(move || match fs.timed("open", || fs.do_open(ino, flags)) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(e),
+        });
     }
+
     fn release(&mut self,  _req: &Request, ino: u64, fh: u64,  _flags: u32, 
                _lock_owner: u64, _flush: bool, reply: ReplyEmpty) {
-        println!("close {:?}", ino);
-        self.open_files.remove(&fh);
+        trace!("release ino={} fh={}", ino, fh);
+        let _ = self.fs.timed("release", || self.fs.open_files.remove(fh));
         reply.ok();
     }
 
     fn read (&mut self, _req: &Request, ino: u64, fh: u64, offset: u64, size: u32, reply: ReplyData) {
-        //TODO: i dont know if offset can be different than the last returned read size
-        println!("read {:?} {} {}", ino, offset, size);
-
-        let file = self.open_files.get_mut(&fh).unwrap();
-
-        let mut buf = vec![0; size as usize];
-        let r = file.read(&mut buf).unwrap();
-        reply.data(&buf[..r]);
-    }
-
-    fn readdir (&mut self, _req: &Request, ino: u64, _fh: u64, offset: u64, mut reply: ReplyDirectory) {
-        println!("readdir {:?}", ino);
-        if offset != 0 {
-            reply.error(ENOENT);
-            return;
-        }
-        match self.index.inodes.get((ino - 1) as usize) {
-            None => reply.error(ENOENT),
-            Some(entry) => {
-                reply.add(1, 0, FileType::Directory, "."); //FIXME
-                reply.add(1, 1, FileType::Directory, "..");
-
-                let mut offset = 2;
-
-                match entry.d {
-                    None => reply.ok(),
-                    Some(ref dir) => {
-                        for (s,d) in dir {
-                            reply.add(d.i, offset, match d.k {
-                                1 => FileType::Directory,
-                                _ => FileType::RegularFile,
-                            }, s);
-                            offset += 1;
-                        }
-                        reply.ok();
+        trace!("read ino={} fh={} offset={} size={}", ino, fh, offset, size);
+        let fs = self.fs;
+        self.pool.execute(move || match fs.timed("read", || fs.do_read(fh, offset, size)) {
+            Ok((buf, next)) => {
+                fs.metrics.add_read(buf.len() as u64);
+                reply.data(&buf);
+                // the reply doesn't wait for the next block, only this worker does
+                if let Some(next) = next {
+                    fs.readahead(&next);
+                }
+            },
+            Err(e) => reply.error(e),
+        });
+    }
+
+    fn write(&mut self, _req: &Request, _ino: u64, fh: u64, offset: u64, data: &[u8], _flags: u32, reply: ReplyWrite) {
+        let (fs, data) = (self.fs, data.to_vec());
+        self.pool.execute(move || match fs.timed("write", || fs.do_write(fh, offset, &data)) {
+            Ok(n) => reply.written(n),
+            Err(e) => reply.error(e),
+        });
+    }
+
+    fn create(&mut self, _req: &Request, parent: u64, name: &OsStr, mode: u32, flags: u32, reply: ReplyCreate) {
+        let (fs, name) = (self.fs, name.to_os_string());
+        self.pool.execute(move || match fs.timed("create", || fs.do_create(parent, &name, mode, flags)) {
+            Ok((attr, fh)) => reply.created(&TTL, &attr, 0, fh, 0),
+            Err(e) => reply.error(e),
+        });
+    }
+
+    fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        let (fs, name) = (self.fs, name.to_os_string());
+        self.pool.execute(move || match fs.timed("mkdir", || fs.do_mkdir(parent, &name, mode)) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        });
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (fs, name) = (self.fs, name.to_os_string());
+        self.pool.execute(move || match fs.timed("unlink", || fs.do_unlink(parent, &name)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        });
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let (fs, name) = (self.fs, name.to_os_string());
+        self.pool.execute(move || match fs.timed("rmdir", || fs.do_rmdir(parent, &name)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        });
+    }
+
+    fn rename(&mut self, _req: &Request, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, reply: ReplyEmpty) {
+        let (fs, name, newname) = (self.fs, name.to_os_string(), newname.to_os_string());
+        self.pool.execute(move || match fs.timed("rename", || fs.do_rename(parent, &name, newparent, &newname)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        });
+    }
+
+    fn destroy(&mut self, _req: &Request) {
+        self.fs.dump_stats();
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let fs = self.fs;
+        self.pool.execute(move || match fs.timed("readlink", || fs.do_readlink(ino)) {
+            Ok(target) => reply.data(&target),
+            Err(e) => reply.error(e),
+        });
+    }
+
+    fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        let (fs, uid, gid) = (self.fs, req.uid(), req.gid());
+        self.pool.execute(move || match fs.timed("access", || fs.do_access(ino, uid, gid, mask)) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        });
+    }
+
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
+        let u = self.fs.usage;
+        self.fs.metrics.record("statfs", None, true);
+        reply.statfs(u.blocks, 0, 0, u.inodes, 0, BLOCK_SIZE, NAME_MAX, BLOCK_SIZE);
+    }
+
+    fn opendir(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
+        let fs = self.fs;
+        self.pool.execute(move || match fs.timed("opendir", || fs.do_opendir(ino)) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(e),
+        });
+    }
+
+    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
+        let _ = self.fs.timed("releasedir", || self.fs.open_dirs.remove(fh));
+        reply.ok();
+    }
+
+    fn readdir (&mut self, _req: &Request, ino: u64, fh: u64, offset: u64, mut reply: ReplyDirectory) {
+        trace!("readdir ino={} fh={} offset={}", ino, fh, offset);
+        let fs = self.fs;
+        self.pool.execute(move || match fs.timed("readdir", || fs.do_readdir(ino, fh)) {
+            Err(e) => reply.error(e),
+            Ok(entries) => {
+                // the offset of an entry is the position of the one after it,
+                // which is where the kernel continues when the reply buffer was full
+                for (n, &(ref s, i, kind)) in entries.iter().enumerate().skip(offset as usize) {
+                    if reply.add(i, n as u64 + 1, kind, s) {
+                        break;
                     }
-                };
+                }
+                reply.ok();
             }
-        }
+        });
     }
 }
 
 impl Inode {
-    fn chain<'a>(&'a self, blockstore: &'a BlockStore) -> Chain<'a, Take<Chain<'a, Take<File>>>> {
-        let c = self.c.as_ref().unwrap();
-        let it = c.iter().map(move |c| {
-            println!("reading from block {} offset  {} limit {}", c.h, c.o, c.l);
-
-            let block = blockstore.get(&c.h).expect("block not found");
-            let mut re = block.chain();
-            re.seek(SeekFrom::Current(c.o as i64)).unwrap();
-            Take::limit(re, c.l as usize)
-
+    /// the content of a file. a block is only looked up when reading gets to it,
+    /// so a missing one is an error from read.
+    pub fn chain<'a>(&'a self, blockstore: &'a BlockStore) -> Chain<'a, Fallible<Decrypt<Take<Chain<'static, RangeReader<'static>>>>>> {
+        let it = self.c.iter().flat_map(|c| c.iter()).map(move |c| {
+            Fallible(blockstore.get(&c.h).and_then(|block| {
+                let mut re = block.into_chain();
+                re.seek(SeekFrom::Current(c.o as i64))?;
+                Ok(Decrypt::new(Take::limit(re, c.l as usize), c.key()?, c.o))
+            }))
         });
         Chain::new(Box::new(it))
     }
 }
+
+
+#[test]
+fn cached_reads() {
+    use blockstore::{memory, test_block};
+    use index::ContentBlockEntry;
+    use std::io::Read;
+
+    let mut bs = memory();
+    bs.blocks.insert(String::from("a"), test_block(vec![("test/readchain/a", 0, 4)]));
+    bs.blocks.insert(String::from("b"), test_block(vec![("test/readchain/b", 0, 10)]));
+
+    let inode = Inode{
+        i: 0, p: 0, s: 9, k: 2, a: 0o644, u: 0, g: 0,
+        d: None,
+        h: None,
+        c: Some(vec![
+            ContentBlockEntry{h: String::from("a"), o: 0, l: 4, e: None},
+            ContentBlockEntry{h: String::from("b"), o: 5, l: 5, e: None},
+        ]),
+        l: None,
+        host_path: ::std::ffi::OsString::new(),
+    };
+    let index = Index{
+        inodes: vec![inode.clone()],
+    };
+    let mut fs = Fuse::new(&index, &bs);
+    fs.cache.verify = false;
+
+    // the next block is only named, loading it is up to the caller
+    let (data, next) = fs.read_at(&inode, 0, 2, true).unwrap();
+    assert_eq!((&data[..], next.as_ref().map(|h| h.as_str())), (&b"ya"[..], Some("b")));
+    assert_eq!(fs.cache.stats().readahead, 0);
+    fs.readahead(&next.unwrap());
+    assert_eq!(fs.cache.stats().readahead, 1);
+    assert_eq!(fs.read_at(&inode, 2, 4, true).unwrap().0, b"yast");
+    assert_eq!(fs.read_at(&inode, 3, 100, false).unwrap().0, b"astuff");
+    assert_eq!(fs.read_at(&inode, 9, 100, false).unwrap().0, b"");
+
+    let s = fs.cache.stats();
+    assert_eq!((s.hits, s.misses), (4, 1));
+
+    let mut missing = inode.clone();
+    missing.c.as_mut().unwrap()[1].h = String::from("c");
+    let mut chain = missing.chain(&bs);
+    assert_eq!(chain.read(&mut [0; 4]).unwrap(), 4);
+    assert_eq!(chain.read(&mut [0; 4]).err().map(|e| e.kind()), Some(io::ErrorKind::NotFound));
+    // a block which can't be read ahead doesn't fail the read before it
+    let (data, next) = fs.read_at(&missing, 0, 2, true).unwrap();
+    assert_eq!(data, b"ya");
+    fs.readahead(&next.unwrap());
+}
+
+#[test]
+fn readdir_listing() {
+    let mut bs = ::blockstore::memory();
+    let (_tmp, index) = ::tarball::fixture("readdir", &mut bs);
+    let fs = Fuse::new(&index, &bs);
+
+    let names = |l: Vec<(OsString, u64, FileType)>| l.into_iter().map(|e| (e.0, e.1)).collect::<Vec<(OsString, u64)>>();
+    let etc = fs.do_lookup(1, OsStr::new("etc")).unwrap().ino;
+    let usr = fs.do_lookup(1, OsStr::new("usr")).unwrap().ino;
+    let share = fs.do_lookup(usr, OsStr::new("share")).unwrap().ino;
+    let link = fs.do_lookup(1, OsStr::new("resolv.conf")).unwrap().ino;
+
+    let root = fs.listing(1).unwrap();
+    assert_eq!(names(root.clone()), vec![
+        (OsString::from("."), 1),
+        (OsString::from(".."), 1),
+        (OsString::from("etc"), etc),
+        (OsString::from("resolv.conf"), link),
+        (OsString::from("usr"), usr),
+    ]);
+    assert_eq!(root[3].2, FileType::Symlink);
+    assert_eq!(names(fs.listing(1).unwrap()), names(root));
+
+    let l = fs.listing(share).unwrap();
+    assert_eq!((l[0].1, l[1].1), (share, usr));
+    assert_eq!(l[2].0, "resolv.conf");
+    assert_eq!(fs.listing(link).err(), Some(ENOTDIR));
+}
+
+#[test]
+fn non_utf8_names() {
+    let tmp = ::tarball::TempDir::new("names");
+    let cafe = OsStr::from_bytes(b"caf\xe9");
+    let dir = OsStr::from_bytes(b"\xff\xfe");
+    fs::create_dir_all(tmp.join(dir)).unwrap();
+    File::create(tmp.join(cafe)).unwrap();
+    File::create(tmp.join(dir).join("caf\u{e9}")).unwrap();
+    ::std::os::unix::fs::symlink(cafe, tmp.join("link")).unwrap();
+    ::std::os::unix::fs::symlink("/etc/hosts", tmp.join("absolute")).unwrap();
+    let built = ::index::from_host(tmp.as_os_str().to_os_string());
+
+    // the index file stays valid json, and gives back the same bytes
+    let json = ::serde_json::to_vec(&built).unwrap();
+    assert!(String::from_utf8(json.clone()).unwrap().contains("\"/636166e9\""));
+    let index = ::index::from_bytes(&json, None).unwrap();
+    let bs = ::blockstore::memory();
+    let fs = Fuse::new(&index, &bs);
+
+    let c = fs.do_lookup(1, cafe).unwrap().ino;
+    let d = fs.do_lookup(1, dir).unwrap().ino;
+    assert_eq!(fs.do_lookup(1, OsStr::new("caf\u{fffd}")).err(), Some(ENOENT));
+    let names : Vec<(OsString, u64)> = fs.listing(1).unwrap().into_iter().skip(2).map(|e| (e.0, e.1)).collect();
+    assert_eq!(names[1..], [(cafe.to_os_string(), c), (OsString::from("link"), names[2].1), (dir.to_os_string(), d)]);
+
+    // so do symlink targets
+    assert!(String::from_utf8(json.clone()).unwrap().contains("[99,97,102,233]"));
+    assert_eq!(fs.do_readlink(fs.do_lookup(1, OsStr::new("link")).unwrap().ino).unwrap(), b"caf\xe9");
+    assert_eq!(fs.do_readlink(fs.do_lookup(1, OsStr::new("absolute")).unwrap().ino).unwrap(), b"/etc/hosts");
+
+    // a valid name in a directory which isn't
+    let e = fs.do_lookup(d, OsStr::new("caf\u{e9}")).unwrap().ino;
+    assert_eq!(fs.path(e).unwrap().as_bytes(), b"\xff\xfe/caf\xc3\xa9");
+}
+
+#[test]
+fn overlay_changes() {
+    use std::ffi::OsString;
+    use std::io::Read;
+    use std::path::Path;
+    use libc::O_RDWR;
+
+    let mut bs = ::blockstore::memory();
+    let (tmp, index) = ::tarball::fixture("overlay", &mut bs);
+    let upper = tmp.join("upper");
+    fs::create_dir_all(&upper).unwrap();
+
+    let ro = &mut Fuse::new(&index, &bs);
+    let etc = ro.do_lookup(1, OsStr::new("etc")).unwrap().ino;
+    assert_eq!(ro.do_unlink(etc, OsStr::new("resolv.conf")), Err(EROFS));
+
+    let mut fs = Fuse::new(&index, &bs);
+    fs.upper = Some(upper.clone());
+    let name = |n: &str| OsString::from(n);
+    let etc = fs.do_lookup(1, &name("etc")).unwrap().ino;
+
+    // new file in an index directory
+    let (hosts, fh) = fs.do_create(etc, &name("hosts"), 0o644, O_WRONLY as u32).unwrap();
+    assert_eq!(fs.do_write(fh, 0, b"127.0.0.1 localhost\n"), Ok(20));
+    assert_eq!(fs.do_read(fh, 10, 5).unwrap().0, b"local");
+    assert_eq!(fs.do_lookup(etc, &name("hosts")).unwrap().ino, hosts.ino);
+    assert_eq!(fs.do_lookup(etc, &name("hosts")).unwrap().size, 20);
+
+    // writing copies up and keeps the inode number
+    let conf = fs.do_lookup(etc, &name("resolv.conf")).unwrap();
+    let fh = fs.do_open(conf.ino, O_RDWR as u32).unwrap();
+    fs.do_write(fh, 11, b"8.8.8.8\n").unwrap();
+    assert_eq!(fs.do_read(fh, 0, 100).unwrap().0, b"nameserver 8.8.8.8\n1\n");
+    assert_eq!(fs.do_lookup(etc, &name("resolv.conf")).unwrap().ino, conf.ino);
+    let attr = fs.do_setattr(conf.ino, Some(0o600), None, None, Some(11)).unwrap();
+    assert_eq!((attr.perm, attr.size), (0o600, 11));
+
+    // the copy up of etc kept its metadata
+    assert_eq!(fs::metadata(upper.join("etc")).unwrap().mode() & 0o7777, 0o700);
+
+    // deleting index entries leaves whiteouts
+    let usr = fs.do_lookup(1, &name("usr")).unwrap().ino;
+    let share = fs.do_lookup(usr, &name("share")).unwrap().ino;
+    assert_eq!(fs.do_rmdir(usr, &name("share")), Err(ENOTEMPTY));
+    assert_eq!(fs.do_unlink(usr, &name("share")), Err(EISDIR));
+    fs.do_unlink(share, &name("resolv.conf")).unwrap();
+    fs.do_rmdir(usr, &name("share")).unwrap();
+    assert!(upper.join("usr/.wh.share").exists());
+    assert_eq!(fs.do_lookup(usr, &name("share")).err(), Some(ENOENT));
+    assert_eq!(fs.do_lookup(usr, &name(".wh.share")).err(), Some(ENOENT));
+    assert_eq!(fs.entries(OsStr::new("usr")).unwrap().len(), 0);
+
+    // a new directory in place of a deleted one starts out empty
+    fs.do_mkdir(usr, &name("share"), 0o755).unwrap();
+    assert_eq!(fs.entries(OsStr::new("usr/share")).unwrap().len(), 0);
+    assert_eq!(fs.do_mkdir(usr, &name("share"), 0o755).err(), Some(EEXIST));
+
+    // renames
+    assert_eq!(fs.do_rename(1, &name("etc"), 1, &name("etc2")), Err(EXDEV));
+    fs.do_rename(etc, &name("hosts"), usr, &name("hosts")).unwrap();
+    fs.do_rename(1, &name("resolv.conf"), usr, &name("link")).unwrap();
+    assert_eq!(fs.path(hosts.ino), Ok(OsString::from("usr/hosts")));
+    let names : Vec<OsString> = fs.entries(OsStr::new("")).unwrap().into_iter().map(|e| e.0).collect();
+    assert_eq!(names, vec!["etc", "usr"]);
+    let names : Vec<OsString> = fs.entries(OsStr::new("usr")).unwrap().into_iter().map(|e| e.0).collect();
+    assert_eq!(names, vec!["hosts", "link", "share"]);
+    assert_eq!(fs::read_link(upper.join("usr/link")).unwrap(), Path::new("etc/resolv.conf"));
+
+    let mut content = String::new();
+    File::open(upper.join("usr/hosts")).unwrap().read_to_string(&mut content).unwrap();
+    assert_eq!(content, "127.0.0.1 localhost\n");
+}
+
+#[test]
+fn inode_round_trips() {
+    use inodes::ROOT;
+
+    let mut bs = ::blockstore::memory();
+    let (tmp, index) = ::tarball::fixture("inodes", &mut bs);
+    let upper = tmp.join("upper");
+    fs::create_dir_all(&upper).unwrap();
+    let mut fs = Fuse::new(&index, &bs);
+    fs.upper = Some(upper.clone());
+    fs.do_create(ROOT, OsStr::new("new"), 0o644, 0).unwrap();
+
+    assert_eq!(fs.path(0).err(), Some(ENOENT));
+    assert_eq!(fs.path(ROOT).and_then(|p| fs.attr(&p)).unwrap().ino, ROOT);
+
+    // every entry of every directory has the same number in readdir, lookup and getattr
+    let mut dirs = vec![ROOT];
+    let mut seen = 0;
+    while let Some(dir) = dirs.pop() {
+        let l = fs.listing(dir).unwrap();
+        assert_eq!(l[0].1, dir);
+        let parent = fs.path(dir).map(|p| overlay::split(&p).0.to_os_string()).unwrap();
+        assert_eq!(l[1].1, if dir == ROOT { ROOT } else { fs.ino(&parent) });
+        for (name, ino, kind) in l.into_iter().skip(2) {
+            assert_eq!(fs.do_lookup(dir, OsStr::new(&name)).unwrap().ino, ino);
+            assert_eq!(fs.path(ino).and_then(|p| fs.attr(&p)).unwrap().ino, ino);
+            if kind == FileType::Directory {
+                dirs.push(ino);
+            }
+            seen += 1;
+        }
+    }
+    // all index nodes but the root, the second name of the hardlink and the new file
+    assert_eq!(seen, index.inodes.len() - 1 + 2);
+
+    // a file created where one was deleted is a different inode
+    let old = fs.do_lookup(ROOT, OsStr::new("new")).unwrap().ino;
+    fs.do_unlink(ROOT, OsStr::new("new")).unwrap();
+    let (attr, _) = fs.do_create(ROOT, OsStr::new("new"), 0o644, 0).unwrap();
+    assert!(attr.ino != old);
+}
+
+#[test]
+fn statfs_access_readlink() {
+    use inodes::ROOT;
+    use libc::F_OK;
+
+    let mut bs = ::blockstore::memory();
+    let (tmp, index) = ::tarball::fixture("access", &mut bs);
+    let upper = tmp.join("upper");
+    fs::create_dir_all(&upper).unwrap();
+    let mut fs = Fuse::new(&index, &bs);
+
+    // resolv.conf is stored once for both names
+    assert_eq!(fs.usage, Usage{size: 21, blocks: 1, inodes: index.inodes.len() as u64});
+
+    let etc = fs.do_lookup(ROOT, OsStr::new("etc")).unwrap();
+    let link = fs.do_lookup(ROOT, OsStr::new("resolv.conf")).unwrap().ino;
+    assert_eq!(fs.do_readlink(link).unwrap(), b"etc/resolv.conf");
+    assert_eq!(fs.do_readlink(etc.ino), Err(EINVAL));
+
+    // etc is 0700
+    let (uid, gid) = (etc.uid, etc.gid);
+    assert_eq!(fs.do_access(etc.ino, uid, gid, (R_OK | X_OK) as u32), Ok(()));
+    assert_eq!(fs.do_access(etc.ino, uid + 1, gid, R_OK as u32), Err(EACCES));
+    assert_eq!(fs.do_access(etc.ino, uid + 1, gid, F_OK as u32), Ok(()));
+    assert_eq!(fs.do_access(etc.ino, 0, 0, (R_OK | X_OK) as u32), Ok(()));
+    assert_eq!(fs.do_access(etc.ino, uid, gid, W_OK as u32), Err(EROFS));
+    fs.upper = Some(upper.clone());
+    assert_eq!(fs.do_access(etc.ino, uid, gid, W_OK as u32), Ok(()));
+    let conf = fs.do_lookup(etc.ino, OsStr::new("resolv.conf")).unwrap().ino;
+    assert_eq!(fs.do_access(conf, 0, 0, X_OK as u32), Err(EACCES));
+
+    // an open directory keeps listing what it had when opened
+    let fh = fs.do_opendir(etc.ino).unwrap();
+    fs.do_create(etc.ino, OsStr::new("hosts"), 0o644, 0).unwrap();
+    assert_eq!(fs.open_dirs.get(fh).unwrap().len(), 3);
+    assert_eq!(fs.listing(etc.ino).unwrap().len(), 4);
+    assert_eq!(fs.do_opendir(conf).err(), Some(ENOTDIR));
+}
+
+#[test]
+fn concurrent_reads() {
+    use inodes::ROOT;
+
+    let mut bs = ::blockstore::memory();
+    let (_tmp, index) = ::tarball::fixture("threads", &mut bs);
+    let fs = Fuse::new(&index, &bs);
+    let etc = fs.do_lookup(ROOT, OsStr::new("etc")).unwrap().ino;
+
+    // every worker has its own handle and reads the file in pieces
+    let results = Mutex::new(Vec::new());
+    workers::scope(4, |pool| {
+        for _ in 0..32 {
+            let (fs, results) = (&fs, &results);
+            pool.execute(move || {
+                let conf = fs.do_lookup(etc, OsStr::new("resolv.conf")).unwrap().ino;
+                let fh = fs.do_open(conf, O_RDONLY as u32).unwrap();
+                let mut content = Vec::new();
+                for offset in (0..21).step_by(4) {
+                    content.extend(fs.do_read(fh, offset, 4).unwrap().0);
+                }
+                fs.open_files.remove(fh).unwrap();
+                results.lock().unwrap().push(content);
+            });
+        }
+    });
+
+    let results = results.into_inner().unwrap();
+    assert_eq!(results.len(), 32);
+    assert!(results.iter().all(|c| &c[..] == b"nameserver 127.0.0.1\n"));
+    assert_eq!(fs.open_files.len(), 0);
+    let s = fs.cache.stats();
+    assert_eq!(s.hits + s.misses, 32 * 6);
+}
+
+#[test]
+fn file_handles() {
+    use inodes::ROOT;
+    use libc::EMFILE;
+
+    let mut bs = ::blockstore::memory();
+    let (_tmp, index) = ::tarball::fixture("handles", &mut bs);
+    let mut fs = Fuse::new(&index, &bs);
+    fs.open_files = handles::new(64);
+    let etc = fs.do_lookup(ROOT, OsStr::new("etc")).unwrap().ino;
+    let conf = fs.do_lookup(etc, OsStr::new("resolv.conf")).unwrap().ino;
+
+    // the same inode opened from many threads at once gets a new handle every time
+    let fhs = Mutex::new(Vec::new());
+    workers::scope(8, |pool| {
+        for _ in 0..64 {
+            let (fs, fhs) = (&fs, &fhs);
+            pool.execute(move || {
+                let fh = fs.do_open(conf, O_RDONLY as u32).unwrap();
+                assert_eq!(fs.do_read(fh, 11, 3).unwrap().0, b"127");
+                fhs.lock().unwrap().push(fh);
+            });
+        }
+    });
+    let mut fhs = fhs.into_inner().unwrap();
+    fhs.sort();
+    fhs.dedup();
+    assert_eq!(fhs.len(), 64);
+    assert_eq!(fs.do_open(conf, O_RDONLY as u32), Err(EMFILE));
+
+    // released handles are gone for good
+    for &fh in fhs.iter() {
+        fs.open_files.remove(fh).unwrap();
+    }
+    assert_eq!(fs.do_read(fhs[0], 0, 10).err(), Some(EBADF));
+    assert_eq!(fs.do_write(fhs[0], 0, b"x"), Err(EBADF));
+    assert_eq!(fs.do_read(0, 0, 10).err(), Some(EBADF));
+    let fh = fs.do_open(conf, O_RDONLY as u32).unwrap();
+    assert!(fh > fhs[63]);
+}
+
+#[test]
+fn encrypted_reads() {
+    use inodes::ROOT;
+    use serializer::Chunker;
+    use std::io::Read;
+
+    let tmp = ::tarball::TempDir::new("encrypted");
+    let build = |secret, bs: &mut ::blockstore::MemoryStore| {
+        let mut chunker = Chunker::loose(&tmp);
+        chunker.secret = Some(secret);
+        ::tarball::from_tar_with(&::tarball::tar_fixture()[..], bs, chunker).unwrap()
+    };
+    let mut bs = ::blockstore::memory();
+    let index = build([7; 32], &mut bs);
+    // the index only has the key of every block, not the secret they come from
+    assert!(!::serde_json::to_string(&index).unwrap().contains(&crypto::to_hex(&[7; 32])));
+
+    // the store only ever sees ciphertext
+    let etc = &index.inodes[index.inodes[0].d.as_ref().unwrap()[OsStr::new("etc")].i as usize];
+    let conf = &index.inodes[etc.d.as_ref().unwrap()[OsStr::new("resolv.conf")].i as usize];
+    let entry = &conf.c.as_ref().unwrap()[0];
+    assert!(entry.e.is_some());
+    let mut stored = Vec::new();
+    bs.get(&entry.h).unwrap().chain().read_to_end(&mut stored).unwrap();
+    assert_eq!(stored.len(), 21);
+    assert!(!stored.windows(10).any(|w| w == b"nameserver"));
+
+    let mut content = String::new();
+    conf.chain(&bs).read_to_string(&mut content).unwrap();
+    assert_eq!(content, "nameserver 127.0.0.1\n");
+
+    let fs = Fuse::new(&index, &bs);
+    let etc = fs.do_lookup(ROOT, OsStr::new("etc")).unwrap().ino;
+    let ino = fs.do_lookup(etc, OsStr::new("resolv.conf")).unwrap().ino;
+    let fh = fs.do_open(ino, O_RDONLY as u32).unwrap();
+    assert_eq!(fs.do_read(fh, 11, 3).unwrap().0, b"127");
+
+    // same content and secret, same block. another secret, another block.
+    let again = build([7; 32], &mut bs);
+    assert_eq!(again.inodes[conf.i as usize].c.as_ref().unwrap()[0].h, entry.h);
+    let other = build([8; 32], &mut bs);
+    assert!(other.inodes[conf.i as usize].c.as_ref().unwrap()[0].h != entry.h);
+}
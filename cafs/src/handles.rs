@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use libc::{c_int, EBADF, EMFILE};
+
+
+/// open handles allowed at the same time
+pub const DEFAULT_CAPACITY: usize = 65536;
+
+struct Inner<T> {
+    next: u64,
+    open: HashMap<u64, Arc<T>>,
+}
+
+/// state of open files or directories by the handle given to the kernel.
+/// handles count up from 1 and are never reused, so a stale one can't reach another file.
+pub struct Handles<T> {
+    capacity: usize,
+    inner:    Mutex<Inner<T>>,
+}
+
+pub fn new<T>(capacity: usize) -> Handles<T> {
+    Handles{
+        capacity: capacity,
+        inner:    Mutex::new(Inner{
+            next: 1,
+            open: HashMap::new(),
+        }),
+    }
+}
+
+impl<T> Handles<T> {
+    pub fn insert(&self, value: T) -> Result<u64, c_int> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.open.len() >= self.capacity {
+            return Err(EMFILE);
+        }
+        let fh = inner.next;
+        inner.next += 1;
+        inner.open.insert(fh, Arc::new(value));
+        Ok(fh)
+    }
+
+    pub fn get(&self, fh: u64) -> Result<Arc<T>, c_int> {
+        self.inner.lock().unwrap().open.get(&fh).cloned().ok_or(EBADF)
+    }
+
+    /// the handle is gone right away, requests still using it keep their reference
+    pub fn remove(&self, fh: u64) -> Result<Arc<T>, c_int> {
+        self.inner.lock().unwrap().open.remove(&fh).ok_or(EBADF)
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().open.len()
+    }
+}
+
+
+#[test]
+fn monotonic_handles() {
+    let h = new(2);
+    assert_eq!(h.insert("a"), Ok(1));
+    assert_eq!(h.insert("b"), Ok(2));
+    assert_eq!(h.insert("c"), Err(EMFILE));
+    assert_eq!(*h.get(2).unwrap(), "b");
+
+    let a = h.remove(1).unwrap();
+    assert_eq!(*a, "a");
+    assert_eq!(h.remove(1).err(), Some(EBADF));
+    assert_eq!(h.get(1).err(), Some(EBADF));
+    assert_eq!(h.get(0).err(), Some(EBADF));
+
+    // freed room, but not the number
+    assert_eq!(h.insert("c"), Ok(3));
+    assert_eq!(h.len(), 2);
+}
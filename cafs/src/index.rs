@@ -1,6 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, VecDeque};
 use std;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::io::{self, Read, Write, BufRead, BufReader, BufWriter};
+use std::fs::File;
+use std::path::Path;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error;
+use serde_json;
+use filter::Filter;
+use crypto;
 
+/// there are no timestamps, an inode only depends on the name, content, mode and owner of a
+/// file. so building the same tree again gives the same index.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Inode {
     pub i: u64,     //inode (might later use this as offset into the binary formated index)
@@ -8,10 +21,17 @@ pub struct Inode {
     pub s: u64,     //size
     pub k: u16,     //kind
     pub a: u16,     //perms
+    #[serde(default)]
+    pub u: u32,     //owner uid
+    #[serde(default)]
+    pub g: u32,     //owner gid
 
-    pub d: Option<HashMap<String, ContentDirEntry>>, //directory
+    #[serde(default, with = "names")]
+    pub d: Option<BTreeMap<OsString, ContentDirEntry>>, //directory, sorted by name
     pub h: Option<String>, //file hash
     pub c: Option<Vec<ContentBlockEntry>>, //content blocks
+    #[serde(default, with = "target")]
+    pub l: Option<OsString>, //symlink target
 
     #[serde(skip)]
     pub host_path: std::ffi::OsString, // full path. will not be stored
@@ -22,6 +42,81 @@ pub struct ContentBlockEntry {
     pub h: String,  //block hash
     pub o: u64,     //offset into block
     pub l: u64,     //length into block
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>, //key the block is encrypted with
+}
+
+impl ContentBlockEntry {
+    pub fn key(&self) -> io::Result<Option<crypto::Key>> {
+        match self.e {
+            Some(ref e) => crypto::key_from_hex(e).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// names are bytes, which the index file keeps as strings. a name which isn't valid utf-8 is
+/// written as "/" followed by its bytes in hex, which can't be a name since it has a "/" in it.
+mod names {
+    use super::*;
+
+    pub fn encode(name: &OsStr) -> String {
+        match name.to_str() {
+            Some(s) => String::from(s),
+            None => format!("/{}", crypto::to_hex(name.as_bytes())),
+        }
+    }
+
+    pub fn decode(s: &str) -> io::Result<OsString> {
+        if s.starts_with('/') {
+            Ok(OsString::from_vec(crypto::from_hex(&s[1..])?))
+        } else {
+            Ok(OsString::from(s))
+        }
+    }
+
+    pub fn serialize<S: Serializer>(d: &Option<BTreeMap<OsString, ContentDirEntry>>, s: S) -> Result<S::Ok, S::Error> {
+        d.as_ref()
+            .map(|d| d.iter().map(|(name, e)| (encode(name), e)).collect::<BTreeMap<String, &ContentDirEntry>>())
+            .serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<BTreeMap<OsString, ContentDirEntry>>, D::Error> {
+        match Option::<BTreeMap<String, ContentDirEntry>>::deserialize(d)? {
+            None => Ok(None),
+            Some(m) => m.into_iter()
+                .map(|(name, e)| decode(&name).map(|n| (n, e)).map_err(|e| D::Error::custom(e.to_string())))
+                .collect::<Result<BTreeMap<_, _>, _>>()
+                .map(Some),
+        }
+    }
+}
+
+/// symlink targets are bytes as well, but unlike names they can start with "/". a target
+/// which isn't valid utf-8 is written as a list of its bytes instead.
+mod target {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Target {
+        Text(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(l: &Option<OsString>, s: S) -> Result<S::Ok, S::Error> {
+        l.as_ref().map(|l| match l.to_str() {
+            Some(t) => Target::Text(String::from(t)),
+            None    => Target::Bytes(l.as_bytes().to_vec()),
+        }).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<OsString>, D::Error> {
+        Ok(Option::<Target>::deserialize(d)?.map(|t| match t {
+            Target::Text(t)  => OsString::from(t),
+            Target::Bytes(b) => OsString::from_vec(b),
+        }))
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -35,41 +130,72 @@ pub struct Index {
     pub inodes:  Vec<Inode>,
 }
 
-fn collect_dir(path: std::ffi::OsString) -> std::io::Result<Vec<std::fs::DirEntry>> {
+fn collect_dir(path: &Path) -> std::io::Result<Vec<std::fs::DirEntry>> {
     let entry_set = try!(std::fs::read_dir(path));
     let mut entries = try!(entry_set.collect::<Result<Vec<_>, _>>());
     entries.sort_by(|a, b| a.path().cmp(&b.path()));
     Ok(entries)
 }
 
-impl Index {
-    fn add_from_dir_entry(&mut self, parent_inode: u64, path: std::fs::DirEntry) -> (String, ContentDirEntry) {
-        let meta = path.metadata().unwrap();
-        let i = (self.inodes.len()) as u64;
+/// entries of the host directory at path, which is at rel in the walk, sorted and
+/// without what the filter excludes
+pub fn host_entries(path: &Path, rel: &[u8], filter: &Filter) -> io::Result<Vec<std::fs::DirEntry>> {
+    let mut entries = collect_dir(path)?;
+    entries.retain(|e| {
+        let is_dir = e.file_type().map(|t| t.is_dir()).unwrap_or(false);
+        !filter.is_excluded(&rel_path(rel, e), is_dir)
+    });
+    Ok(entries)
+}
 
-        let kind = match meta.is_dir() {
-            true  => 1,
-            false => 2,
-        };
+pub fn kind(meta: &std::fs::Metadata) -> u16 {
+    if meta.file_type().is_symlink() {
+        3
+    } else if meta.is_dir() {
+        1
+    } else {
+        2
+    }
+}
 
-        let entry = Inode{
-            i:  i,
-            p: parent_inode,
-            s: meta.len(),
-            k: kind,
-            a: 0o775,
+/// the inode of a file on the host, before its content is chunked
+pub fn host_inode(i: u64, parent: u64, path: &Path, meta: &std::fs::Metadata) -> io::Result<Inode> {
+    let kind = kind(meta);
+    let link = match kind {
+        3 => Some(std::fs::read_link(path)?.into_os_string()),
+        _ => None,
+    };
+
+    Ok(Inode{
+        i:  i,
+        p: parent,
+        // the size of a directory depends on the filesystem, not on what's in it
+        s: if kind == 1 { 0 } else { meta.len() },
+        k: kind,
+        a: (meta.mode() & 0o7777) as u16,
+        u: meta.uid(),
+        g: meta.gid(),
+
+        d: None,
+        h: None,
+        c: Some(Vec::new()),
+        l: link,
 
-            d: None,
-            h: None,
-            c: Some(Vec::new()),
+        host_path: path.as_os_str().to_os_string(),
+    })
+}
 
-            host_path: path.path().into_os_string(),
-        };
+impl Index {
+    fn add_from_dir_entry(&mut self, parent_inode: u64, path: std::fs::DirEntry) -> (OsString, ContentDirEntry) {
+        let meta = path.metadata().unwrap();
+        let i = (self.inodes.len()) as u64;
+        let entry = host_inode(i, parent_inode, &path.path(), &meta).unwrap();
+        let kind = entry.k;
 
         self.inodes.push(entry);
 
         (
-            path.file_name().to_string_lossy().into_owned(),
+            path.file_name(),
             ContentDirEntry {
                 i: i,
                 k: kind,
@@ -77,37 +203,98 @@ impl Index {
         )
     }
 
-    fn descend(&mut self, parent_inode: u64, path: std::ffi::OsString) {
+    /// inodes are numbered breadth first: the entries of a directory get the next free
+    /// numbers when it is its turn to be listed. this is the order an index can be
+    /// written in while walking, see stream.
+    fn descend(&mut self, root: &Path, filter: &Filter, dev: u64) {
+        let mut dirs = VecDeque::new();
+        dirs.push_back(0);
+        while let Some(x) = dirs.pop_front() {
+            let path = std::path::PathBuf::from(self.inodes[x as usize].host_path.clone());
+            if x != 0 && filter.one_file_system && std::fs::symlink_metadata(&path).unwrap().dev() != dev {
+                // keep the mount point itself, but not what's mounted on it
+                self.inodes[x as usize].d = Some(BTreeMap::new());
+                continue;
+            }
+            let rel = path.strip_prefix(root).unwrap().as_os_str().as_bytes().to_vec();
 
-        let dirs = collect_dir(path).unwrap();
+            let mut contentdirmap : BTreeMap<OsString, ContentDirEntry> = BTreeMap::new();
+            for e in host_entries(&path, &rel, filter).unwrap() {
+                let (name, cde) = self.add_from_dir_entry(x, e);
+                if cde.k == 1 {
+                    dirs.push_back(cde.i);
+                }
+                contentdirmap.insert(name, cde);
+            }
+            self.inodes[x as usize].d = Some(contentdirmap);
+        }
+    }
+}
 
-        let inode_start = self.inodes.len() as u64;
-        let inode_len   = dirs.len() as u64;
+/// path of a dir entry relative to the root of the walk
+fn rel_path(parent: &[u8], e: &std::fs::DirEntry) -> Vec<u8> {
+    let mut p = parent.to_vec();
+    if !p.is_empty() {
+        p.push(b'/');
+    }
+    p.extend_from_slice(e.file_name().as_bytes());
+    p
+}
 
-        // 1 iteration to create all the inodes
-        let mut contentdirmap : HashMap<String, ContentDirEntry> = HashMap::new();
-        for path in dirs {
-            let (name, cde) = self.add_from_dir_entry(parent_inode, path);
-            contentdirmap.insert(name, cde);
-        }
+impl Index {
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let f = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(f, self)?;
+        Ok(())
+    }
 
-        // insert the dirmap into the current parent node
-        self.inodes[parent_inode as usize].d = Some(contentdirmap);
-
-        // 2. iteration to descend into the subdirs
-        for x in inode_start..(inode_start+inode_len) {
-            let (kind, inode, path) = {
-                let ref e = self.inodes[x as usize];
-                (e.k, e.i, e.host_path.clone())
-            };
-            if kind == 1 {
-                self.descend(inode, path);
+    /// give every node the same owner, so the index doesn't depend on who built it
+    pub fn set_owner(&mut self, uid: Option<u32>, gid: Option<u32>) {
+        for inode in self.inodes.iter_mut() {
+            if let Some(uid) = uid {
+                inode.u = uid;
+            }
+            if let Some(gid) = gid {
+                inode.g = gid;
             }
         }
     }
+
+    /// save encrypted with a master key, which then has to be given to load_sealed
+    pub fn save_sealed<P: AsRef<Path>>(&self, path: P, master: &[u8]) -> io::Result<()> {
+        let sealed = crypto::seal(master, serde_json::to_vec(self)?)?;
+        File::create(path)?.write_all(&sealed)
+    }
+}
+
+/// load an index, which may be sealed with master
+pub fn load_sealed<P: AsRef<Path>>(path: P, master: Option<&[u8]>) -> io::Result<Index> {
+    let mut f = BufReader::new(File::open(path)?);
+    if !crypto::is_sealed(f.fill_buf()?) {
+        return Ok(serde_json::from_reader(f)?);
+    }
+    let mut data = Vec::new();
+    f.read_to_end(&mut data)?;
+    from_bytes(&data, master)
+}
+
+/// an index from the content of an index file
+pub fn from_bytes(data: &[u8], master: Option<&[u8]>) -> io::Result<Index> {
+    if !crypto::is_sealed(data) {
+        return Ok(serde_json::from_slice(data)?);
+    }
+    let master = master.ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied,
+                                                     "index is sealed, a master key is needed"))?;
+    Ok(serde_json::from_slice(&crypto::open(master, data)?)?)
 }
 
 pub fn from_host(host: std::ffi::OsString) -> Index{
+    from_host_filtered(host, &Filter::new())
+}
+
+pub fn from_host_filtered(host: std::ffi::OsString, filter: &Filter) -> Index{
+    let meta = std::fs::metadata(&host).unwrap();
+    let dev  = meta.dev();
     let mut index = Index{
         inodes:  Vec::new(),
     };
@@ -117,15 +304,63 @@ pub fn from_host(host: std::ffi::OsString) -> Index{
         p: 0,
         s: 0,
         k: 1,
-        a: 0o775,
+        a: (meta.mode() & 0o7777) as u16,
+        u: meta.uid(),
+        g: meta.gid(),
 
         d: None,
         h: None,
         c: None,
+        l: None,
 
         host_path: host.clone(),
     });
-    index.descend(0, host);
+    index.descend(Path::new(&host), filter, dev);
     index
 }
 
+
+#[test]
+fn filtered_walk() {
+    let mut filter = Filter::new();
+    filter.exclude("*.jpg");
+    filter.exclude("/realdemo/systemb/");
+    filter.include("cat.jpg");
+    filter.one_file_system = true;
+    let index = from_host_filtered(std::ffi::OsString::from("test"), &filter);
+
+    let names : Vec<String> = index.inodes.iter()
+        .map(|i| i.host_path.to_string_lossy().into_owned()).collect();
+
+    assert!(names.contains(&String::from("test/image_same/cat.jpg")));
+    assert!(!names.contains(&String::from("test/image_same/cat2.jpg")));
+    assert!(names.contains(&String::from("test/realdemo/systema/bin/sh")));
+    assert!(!names.iter().any(|n| n.starts_with("test/realdemo/systemb")));
+}
+
+
+#[test]
+fn reproducible_builds() {
+    let build = || {
+        let mut bs = ::blockstore::memory();
+        let mut index = from_host(std::ffi::OsString::from("test/realdemo/systema"));
+        index.serialize_with(&mut bs, ::serializer::Chunker::new()).unwrap();
+        serde_json::to_vec(&index).unwrap()
+    };
+    let a = build();
+    assert!(a == build());
+
+    let mut bs = ::blockstore::memory();
+    let (tmp, mut a) = ::tarball::fixture("reproducible", &mut bs);
+    let mut b = ::tarball::from_tar(&::tarball::tar_fixture()[..], &mut bs, &tmp).unwrap();
+    assert!(serde_json::to_vec(&a).unwrap() == serde_json::to_vec(&b).unwrap());
+
+    // built by someone else
+    for inode in b.inodes.iter_mut() {
+        inode.u += 1000;
+    }
+    a.set_owner(Some(0), Some(0));
+    b.set_owner(Some(0), Some(0));
+    assert!(serde_json::to_vec(&a).unwrap() == serde_json::to_vec(&b).unwrap());
+    assert!(a.inodes.iter().all(|i| i.u == 0 && i.g == 0));
+}
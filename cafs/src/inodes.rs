@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+
+
+/// the kernel always asks for the root of a mount as inode 1, 0 is never a valid inode
+pub const ROOT: u64 = 1;
+
+/// inode number reported for index node i, the index root being at 0
+pub fn from_index(i: u64) -> u64 {
+    i + ROOT
+}
+
+/// the one place where fuse inode numbers and paths are tied together.
+/// index nodes are numbered after their position in the index, everything else
+/// gets a number past the end of the index the first time it is seen.
+/// numbers are dropped again when the kernel forgets them or their node is removed.
+pub struct InodeMap {
+    paths:   HashMap<u64, OsString>, // path of every inode the kernel knows about
+    extra:   HashMap<OsString, u64>, // inode numbers of nodes which are not in the index
+    lookups: HashMap<u64, u64>,      // entries handed to the kernel and not forgotten yet
+    next:    u64,
+}
+
+pub fn new(index_len: usize) -> InodeMap {
+    let mut paths = HashMap::new();
+    paths.insert(ROOT, OsString::new());
+    InodeMap{
+        paths:   paths,
+        extra:   HashMap::new(),
+        lookups: HashMap::new(),
+        next:    from_index(index_len as u64),
+    }
+}
+
+impl InodeMap {
+    /// inode number of path, lower being the index node at path if there is one.
+    /// a number once handed out for a path is kept, so index nodes keep theirs when copied up.
+    pub fn get<P: AsRef<OsStr>>(&mut self, path: P, lower: Option<u64>) -> u64 {
+        let path = path.as_ref();
+        let ino = match (self.extra.get(path), lower) {
+            (Some(&ino), _) => ino,
+            _ if path.is_empty() => ROOT,
+            (None, Some(i)) => from_index(i),
+            (None, None) => {
+                let ino = self.next;
+                self.next += 1;
+                self.extra.insert(path.to_os_string(), ino);
+                ino
+            },
+        };
+        self.paths.insert(ino, path.to_os_string());
+        ino
+    }
+
+    pub fn path(&self, ino: u64) -> Option<&OsString> {
+        self.paths.get(&ino)
+    }
+
+    /// the kernel was given an entry for ino, it holds on to it until it forgets it
+    pub fn lookup(&mut self, ino: u64) {
+        *self.lookups.entry(ino).or_insert(0) += 1;
+    }
+
+    /// the kernel dropped n of its lookups of ino. once none are left its path is
+    /// no longer needed, and a node outside the index gets a new number next time.
+    pub fn forget(&mut self, ino: u64, n: u64) {
+        let left = self.lookups.get(&ino).map(|&l| l.saturating_sub(n)).unwrap_or(0);
+        if left > 0 {
+            self.lookups.insert(ino, left);
+            return;
+        }
+        self.lookups.remove(&ino);
+        if ino == ROOT {
+            return;
+        }
+        if let Some(path) = self.paths.remove(&ino) {
+            if self.extra.get(&path) == Some(&ino) {
+                self.extra.remove(&path);
+            }
+        }
+    }
+
+    /// path was deleted, a new node created there must not get its number.
+    /// the kernel may still use the number until it forgets it.
+    pub fn remove<P: AsRef<OsStr>>(&mut self, path: P) {
+        let path = path.as_ref();
+        let mut prefix = path.as_bytes().to_vec();
+        prefix.push(b'/');
+        self.extra.retain(|p, _| p != path && !p.as_bytes().starts_with(&prefix));
+    }
+
+    /// src was moved to dst as inode ino, along with everything below it
+    pub fn rename<P: AsRef<OsStr>>(&mut self, src: P, dst: P, ino: u64) {
+        let (src, dst) = (src.as_ref(), dst.as_ref());
+        let mut prefix = src.as_bytes().to_vec();
+        prefix.push(b'/');
+        let moved = |p: &OsString| if p == src {
+            Some(dst.to_os_string())
+        } else if p.as_bytes().starts_with(&prefix) {
+            let mut n = dst.to_os_string();
+            n.push("/");
+            n.push(OsStr::from_bytes(&p.as_bytes()[prefix.len()..]));
+            Some(n)
+        } else {
+            None
+        };
+        for p in self.paths.values_mut() {
+            if let Some(n) = moved(p) {
+                *p = n;
+            }
+        }
+        self.extra = self.extra.drain().map(|(p, i)| (moved(&p).unwrap_or(p), i)).collect();
+        self.extra.insert(dst.to_os_string(), ino);
+        self.paths.insert(ino, dst.to_os_string());
+    }
+}
+
+
+#[test]
+fn inode_numbers() {
+    let mut m = new(3);
+    assert_eq!(m.path(ROOT).map(|p| p.as_os_str()), Some(OsStr::new("")));
+    assert_eq!(m.path(0), None);
+    assert_eq!(m.get("", Some(0)), ROOT);
+    assert_eq!(m.get("", None), ROOT);
+
+    assert_eq!(m.get("etc", Some(1)), 2);
+    assert_eq!(m.get("etc/hosts", None), 4);
+    assert_eq!(m.get("etc/hosts", None), 4);
+    assert_eq!(m.get("usr", None), 5);
+    assert_eq!(m.path(4).map(|p| p.as_os_str()), Some(OsStr::new("etc/hosts")));
+
+    // moved nodes keep their number, also when an index node shows up under the old name
+    m.rename("etc", "usr/etc", 2);
+    assert_eq!(m.path(2).map(|p| p.as_os_str()), Some(OsStr::new("usr/etc")));
+    assert_eq!(m.path(4).map(|p| p.as_os_str()), Some(OsStr::new("usr/etc/hosts")));
+    assert_eq!(m.get("usr/etc/hosts", None), 4);
+    assert_eq!(m.get("usr/etc", Some(1)), 2);
+    assert_eq!(m.get("etc", None), 6);
+
+    // a path deleted and created again is a new node
+    m.remove("etc");
+    assert_eq!(m.get("etc", None), 7);
+    assert_eq!(m.path(6).map(|p| p.as_os_str()), Some(OsStr::new("etc")));
+
+    // forgotten numbers go away once the last lookup is dropped
+    m.lookup(7);
+    m.lookup(7);
+    m.forget(7, 1);
+    assert_eq!(m.get("etc", None), 7);
+    m.forget(7, 1);
+    assert_eq!(m.path(7), None);
+    assert_eq!(m.get("etc", None), 8);
+    m.lookup(2);
+    m.forget(2, 1);
+    assert_eq!(m.path(2), None);
+    assert_eq!(m.get("usr/etc", Some(1)), 2);
+    m.forget(ROOT, 1);
+    assert_eq!(m.path(ROOT).map(|p| p.as_os_str()), Some(OsStr::new("")));
+}
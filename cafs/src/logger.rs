@@ -0,0 +1,36 @@
+use std::env;
+use std::io::{self, Write};
+use log::{self, Log, LevelFilter, Metadata, Record};
+
+
+/// level used when CAFS_LOG isn't set
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// one line per record on stderr, so stdout stays free for tars and bundles
+struct Stderr;
+
+static LOGGER: Stderr = Stderr;
+
+impl Log for Stderr {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let _ = writeln!(io::stderr(), "{:<5} {}: {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+/// log at the level given by CAFS_LOG: off, error, warn, info, debug or trace
+pub fn init() {
+    let level = env::var("CAFS_LOG").ok().and_then(|l| l.parse().ok()).unwrap_or(DEFAULT_LEVEL);
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
@@ -2,6 +2,7 @@ extern crate fuse;
 extern crate time;
 extern crate libc;
 extern crate serde;
+#[macro_use]
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
@@ -9,24 +10,266 @@ extern crate sha2;
 extern crate digest;
 extern crate rollsum;
 extern crate pbr;
+extern crate tar;
+extern crate flate2;
+extern crate zstd;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
 
 use std::env;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+use std::io::{Read, Write};
+
+use blockstore::BlockStore;
 
 mod fs;
 mod serializer;
 mod index;
 mod blockstore;
 mod readchain;
+mod filter;
+mod tarball;
+mod export;
+mod diff;
+mod delta;
+mod remote;
+mod packfile;
+mod cache;
+mod fdpool;
+mod overlay;
+mod commit;
+mod inodes;
+mod handles;
+mod workers;
+mod logger;
+mod metrics;
+mod crypto;
+mod ed25519;
+mod signature;
+mod merkle;
+mod progress;
+mod stream;
+
+
+
+fn usage() -> ! {
+    println!("usage: cafs [-x] [--exclude PATTERN] [--include PATTERN] [--exclude-from FILE]");
+    println!("            [--blocks DIR | --pack FILE] [--index FILE] [--encrypt KEYFILE] [--uid N] [--gid N]");
+    println!("            [--stream] [--progress bar|json|none] <dir> [mountpoint]");
+    println!("       cafs --blocks DIR [--pack FILE] [--index FILE] [--encrypt KEYFILE] [--uid N] [--gid N] - [mountpoint]");
+    println!("                                        read a tar, tar.gz or tar.zst from stdin");
+    println!("                                        with --encrypt, blocks are stored encrypted with keys");
+    println!("                                        from the secret in KEYFILE. the index only holds the");
+    println!("                                        block keys. a new secret is written there if there is");
+    println!("                                        none yet, builds with the same one share their blocks");
+    println!("                                        --uid and --gid set the owner of every file");
+    println!("                                        --stream writes the index while walking <dir>, without");
+    println!("                                        keeping it in memory. needs --index and a STORE");
+    println!("                                        --progress json prints a json object per line instead");
+    println!("                                        of a bar, with bytes, blocks and an eta in seconds");
+    println!("       cafs export --index FILE STORE [--remote URL] (--tar | --dir PATH)");
+    println!("       cafs diff [--json] OLD_INDEX NEW_INDEX");
+    println!("       cafs missing [--json] --index FILE STORE [--remote URL]");
+    println!("                                        list blocks of an image not in STORE. blocks can be");
+    println!("                                        larger than the part the image uses, the number of");
+    println!("                                        bytes is only exact with --remote to ask for their size");
+    println!("       cafs bundle STORE [--want FILE | --index FILE]         pack blocks into a bundle on stdout,");
+    println!("                                        all blocks in STORE unless a list or an image is given");
+    println!("       cafs unbundle --blocks DIR [--pack FILE]               unpack a bundle from stdin");
+    println!("       cafs mount --index FILE STORE [--remote URL] [--upper DIR] [--cache MB] [--threads N]");
+    println!("                  [--files N] [--latency] [--verify PUBKEY [--signature SIG]] MOUNTPOINT");
+    println!("                                        with --upper, changes are written to DIR.");
+    println!("                                        --files bounds the open block files, 256 by default");
+    println!("                                        SIGUSR1 logs request counts, with --latency also latencies");
+    println!("                                        with --verify, the index has to be signed by PUBKEY");
+    println!("       cafs commit --index FILE STORE --upper DIR --output FILE [--encrypt KEYFILE]");
+    println!("                                        snapshot a mount with its changes into a new index.");
+    println!("                                        an encrypted image needs the KEYFILE it was built with");
+    println!("       cafs keygen FILE                       new signing key in FILE, its public key in FILE.pub");
+    println!("       cafs sign --index FILE --key FILE [--signature SIG]");
+    println!("       cafs verify --index FILE --key PUBKEY [--signature SIG]");
+    println!("                                        SIG defaults to the index file name with .sig appended");
+    println!("       cafs hash --index FILE [PATH]          root hash of the image, or of the subtree at PATH");
+    println!();
+    println!("STORE is --blocks DIR for loose blocks or --pack FILE for a packfile");
+    println!("CAFS_LOG sets the log level: off, error, warn, info (default), debug or trace");
+    println!("CAFS_MASTER_KEY names a key file, index files are written and read encrypted with it");
+    std::process::exit(1);
+}
+
+/// the local store given by --pack or --blocks. with a remote, blocks missing locally are
+/// fetched on demand, using the block directory as download cache.
+fn open_store(blockdir: Option<OsString>, pack: Option<OsString>, remote: Option<OsString>) -> Box<BlockStore> {
+    let local : Box<BlockStore> = match (pack, &blockdir) {
+        (Some(pack), _) => Box::new(packfile::open(pack).expect("cannot open pack")),
+        (None, &Some(ref blockdir)) => Box::new(blockstore::loose(blockdir).expect("cannot create block directory")),
+        (None, &None) => usage(),
+    };
+    match remote {
+        Some(remote) => {
+            let blockdir = blockdir.unwrap_or_else(|| usage());
+            std::fs::create_dir_all(&blockdir).expect("cannot create block directory");
+            let url = remote.to_str().unwrap_or_else(|| usage());
+            let remote = remote::new(url, &blockdir).expect("invalid remote");
+            Box::new(blockstore::layered(local, Box::new(remote)))
+        },
+        None => local,
+    }
+}
 
+/// contents of the file named by CAFS_MASTER_KEY. when it is set, index files are
+/// written encrypted with it and can't be read without it.
+fn master_key() -> Option<Vec<u8>> {
+    env::var_os("CAFS_MASTER_KEY").map(|path| {
+        let mut key = Vec::new();
+        std::fs::File::open(path).and_then(|mut f| f.read_to_end(&mut key)).expect("cannot read master key");
+        key
+    })
+}
+
+fn load_index<P: AsRef<Path>>(path: P) -> index::Index {
+    let key = master_key();
+    index::load_sealed(path, key.as_ref().map(|k| &k[..])).expect("cannot read index")
+}
+
+/// an index only if it is signed by the key in pubkey
+fn load_verified(indexfile: &OsString, pubkey: &OsString, sigfile: Option<OsString>) -> index::Index {
+    let public = signature::read_public(pubkey).expect("cannot read public key");
+    let sigfile = sigfile.map(std::path::PathBuf::from).unwrap_or_else(|| signature::signature_path(indexfile));
+    let hi = load_index(indexfile);
+    if let Err(e) = signature::verify_index(&public, &hi, sigfile) {
+        eprintln!("cannot verify index: {}", e);
+        std::process::exit(1);
+    }
+    hi
+}
 
+fn save_index<P: AsRef<Path>>(hi: &index::Index, path: P) {
+    match master_key() {
+        Some(key) => hi.save_sealed(path, &key),
+        None => hi.save(path),
+    }.expect("cannot write index")
+}
 
 fn main() {
-    let i   = env::args_os().nth(1).unwrap();
+    logger::init();
+    let args : Vec<OsString> = env::args_os().skip(1).collect();
+    match args.first().and_then(|a| a.to_str()) {
+        Some("export") => export(&args[1..]),
+        Some("diff")   => diff(&args[1..]),
+        Some("missing")  => missing(&args[1..]),
+        Some("bundle")   => bundle(&args[1..]),
+        Some("unbundle") => unbundle(&args[1..]),
+        Some("mount")    => mount(&args[1..]),
+        Some("commit")   => commit(&args[1..]),
+        Some("keygen")   => keygen(&args[1..]),
+        Some("sign")     => sign(&args[1..]),
+        Some("verify")   => verify(&args[1..]),
+        Some("hash")     => hash(&args[1..]),
+        _ => build(&args),
+    }
+}
+
+fn build(args: &[OsString]) {
+    let mut filter = filter::Filter::new();
+    let mut blockdir = None;
+    let mut pack = None;
+    let mut indexfile = None;
+    let mut keyfile = None;
+    let mut uid = None;
+    let mut gid = None;
+    let mut stream = false;
+    let mut progress = String::from("bar");
+    let mut positional = Vec::new();
+
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-x") | Some("--one-file-system") => {
+                filter.one_file_system = true;
+            },
+            Some("--exclude") => {
+                filter.exclude(args.next().unwrap_or_else(|| usage()));
+            },
+            Some("--include") => {
+                filter.include(args.next().unwrap_or_else(|| usage()));
+            },
+            Some("--exclude-from") => {
+                let f = args.next().unwrap_or_else(|| usage());
+                filter.add_file(&f).expect("cannot read filter file");
+            },
+            Some("--blocks") => {
+                blockdir = Some(args.next().unwrap_or_else(|| usage()));
+            },
+            Some("--pack") => {
+                pack = Some(args.next().unwrap_or_else(|| usage()));
+            },
+            Some("--index") => {
+                indexfile = Some(args.next().unwrap_or_else(|| usage()));
+            },
+            Some("--encrypt") => {
+                keyfile = Some(args.next().unwrap_or_else(|| usage()));
+            },
+            Some("--uid") => {
+                uid = Some(args.next().and_then(|a| a.to_str().and_then(|a| a.parse().ok())).unwrap_or_else(|| usage()));
+            },
+            Some("--gid") => {
+                gid = Some(args.next().and_then(|a| a.to_str().and_then(|a| a.parse().ok())).unwrap_or_else(|| usage()));
+            },
+            Some("--stream") => {
+                stream = true;
+            },
+            Some("--progress") => {
+                progress = args.next().and_then(|a| a.into_string().ok()).unwrap_or_else(|| usage());
+            },
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() < 1 {
+        usage();
+    }
+
+    let i   = positional[0].clone();
 
-    let mut bs = blockstore::new();
-    let mut hi = index::from_host(i);
-    hi.serialize(&mut bs);
+    // encrypted blocks only exist in the store, there is no host file to read them from
+    if (stream || keyfile.is_some()) && blockdir.is_none() && pack.is_none() {
+        usage();
+    }
+
+    // without a store, blocks only reference the host files
+    let mut bs : Box<BlockStore> = match (&blockdir, &pack) {
+        (&None, &None) => Box::new(blockstore::memory()),
+        _ => open_store(blockdir.clone(), pack, None),
+    };
+    let secret = keyfile.map(|keyfile| crypto::image_secret(keyfile).expect("cannot read key file"));
+    if stream {
+        build_streaming(&i, &filter, &mut *bs, secret, progress_to(&progress), indexfile, uid, gid);
+        return;
+    }
+    let mut hi = if i == "-" {
+        let blockdir = blockdir.unwrap_or_else(|| usage());
+        let stdin = std::io::stdin();
+        let tar = tarball::decompress(stdin.lock()).expect("cannot read stdin");
+        let mut chunker = serializer::Chunker::loose(Path::new(&blockdir));
+        chunker.secret = secret;
+        chunker.progress = progress_to(&progress);
+        tarball::from_tar_with(tar, &mut *bs, chunker).unwrap()
+    } else {
+        let mut hi = index::from_host_filtered(i, &filter);
+        let mut chunker = serializer::Chunker::new();
+        chunker.secret = secret;
+        chunker.progress = progress_to(&progress);
+        hi.serialize_with(&mut *bs, chunker).unwrap();
+        hi
+    };
+    hi.set_owner(uid, gid);
+
+    if let Some(indexfile) = indexfile {
+        save_index(&hi, &indexfile);
+    }
 
     //let j   = serde_json::to_string(&hi).unwrap();
     //println!("{}", j);
@@ -34,18 +277,397 @@ fn main() {
 
     return;
 
-    let fs = fs::Fuse::new(&hi, &bs);
+    let mut fs = fs::Fuse::new(&hi, &*bs);
 
-    let mountpoint  = env::args_os().nth(2).unwrap();
+    let mountpoint  = positional.get(1).unwrap_or_else(|| usage());
     let fuse_args: Vec<&OsStr> = vec![&OsStr::new("-o"), &OsStr::new("auto_unmount")];
-    fuse::mount(fs, &mountpoint, &fuse_args).unwrap();
+    fs::mount(&fs, workers::DEFAULT_THREADS, &mountpoint, &fuse_args).unwrap();
+}
+
+/// write the index of dir while walking it. the blocks go to a store, so that the
+/// block list doesn't have to stay in memory either.
+fn build_streaming(dir: &OsString, filter: &filter::Filter, bs: &mut BlockStore, secret: Option<crypto::Key>,
+                   progress: Box<progress::Progress>, indexfile: Option<OsString>, uid: Option<u32>, gid: Option<u32>) {
+    let indexfile = indexfile.unwrap_or_else(|| usage());
+    if dir == "-" {
+        usage();
+    }
+    if master_key().is_some() {
+        eprintln!("a sealed index can't be written with --stream");
+        std::process::exit(1);
+    }
+    let mut queue = indexfile.clone();
+    queue.push(".queue");
+
+    let out = std::io::BufWriter::new(std::fs::File::create(&indexfile).expect("cannot write index"));
+    let mut b = stream::Builder::new(out, Path::new(&queue)).expect("cannot create queue");
+    b.uid = uid;
+    b.gid = gid;
+    let mut chunker = serializer::Chunker::new();
+    chunker.secret = secret;
+    chunker.progress = progress;
+    b.build(Path::new(dir), filter, bs, chunker).expect("cannot build index");
+}
+
+/// how a build reports its progress, by the name given to --progress
+fn progress_to(name: &str) -> Box<progress::Progress> {
+    match name {
+        "bar"  => Box::new(progress::bar()),
+        "json" => Box::new(progress::json_lines(std::io::stdout())),
+        "none" => Box::new(progress::Silent),
+        _ => usage(),
+    }
+}
+
+fn export(args: &[OsString]) {
+    let mut blockdir = None;
+    let mut pack = None;
+    let mut indexfile = None;
+    let mut remote = None;
+    let mut tar = false;
+    let mut dir = None;
+
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--blocks") => blockdir  = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--pack")   => pack      = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--index")  => indexfile = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--dir")    => dir       = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--remote") => remote    = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--tar")    => tar       = true,
+            _ => usage(),
+        }
+    }
+
+    let hi = load_index(indexfile.unwrap_or_else(|| usage()));
+    let bs = open_store(blockdir, pack, remote);
+
+    match (tar, dir) {
+        (true, None) => {
+            let stdout = std::io::stdout();
+            export::to_tar(&hi, &*bs, stdout.lock()).and_then(|mut w| w.flush()).expect("export failed");
+        },
+        (false, Some(dir)) => {
+            export::to_dir(&hi, &*bs, dir).expect("export failed");
+        },
+        _ => usage(),
+    }
+}
+
+fn diff(args: &[OsString]) {
+    let mut json = false;
+    let mut positional = Vec::new();
+    for arg in args {
+        match arg.to_str() {
+            Some("--json") => json = true,
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 2 {
+        usage();
+    }
+
+    let a = load_index(positional[0]);
+    let b = load_index(positional[1]);
+    let d = diff::diff(&a, &b);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&d).unwrap());
+    } else {
+        print!("{}", d);
+    }
+}
+
+fn missing(args: &[OsString]) {
+    let mut json = false;
+    let mut blockdir = None;
+    let mut pack = None;
+    let mut indexfile = None;
+    let mut remote = None;
+
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--json")   => json = true,
+            Some("--blocks") => blockdir  = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--pack")   => pack      = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--index")  => indexfile = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--remote") => remote    = Some(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+
+    let hi = load_index(indexfile.unwrap_or_else(|| usage()));
+    // blocks would be fetched from the remote, which knows their size
+    let source = remote.map(|remote| {
+        let url = remote.to_str().unwrap_or_else(|| usage());
+        remote::new(url, blockdir.as_ref().unwrap_or_else(|| usage())).expect("invalid remote")
+    });
+    let bs = open_store(blockdir, pack, None);
+
+    let m = delta::missing(&hi, &*bs, source.as_ref().map(|s| s as &BlockStore));
+    let total = m.iter().fold(0, |acc, b| acc + b.s);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&json!({
+            "blocks": m,
+            "bytes":  total,
+            "exact":  source.is_some(),
+        })).unwrap());
+    } else {
+        for b in &m {
+            println!("{}", b.h);
+        }
+        let at_least = if source.is_some() { "" } else { "at least " };
+        eprintln!("{} blocks missing, {}{} bytes to fetch", m.len(), at_least, total);
+    }
+}
+
+fn bundle(args: &[OsString]) {
+    let mut blockdir = None;
+    let mut pack = None;
+    let mut indexfile = None;
+    let mut want = None;
+
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--blocks") => blockdir  = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--pack")   => pack      = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--index")  => indexfile = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--want")   => want      = Some(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+
+    let bs = open_store(blockdir, pack, None);
+
+    // either an explicit list of hashes as printed by "missing", every block of an image
+    // or everything in the store
+    let hashes : Vec<String> = match (want, indexfile) {
+        (Some(want), None) => {
+            let mut s = String::new();
+            if want == "-" {
+                std::io::stdin().read_to_string(&mut s).expect("cannot read stdin");
+            } else {
+                std::fs::File::open(&want).and_then(|mut f| f.read_to_string(&mut s)).expect("cannot read want list");
+            }
+            s.lines().map(|l| l.trim()).filter(|l| !l.is_empty()).map(String::from).collect()
+        },
+        (None, Some(indexfile)) => {
+            let hi = load_index(indexfile);
+            delta::missing(&hi, &blockstore::memory(), None).into_iter().map(|m| m.h).collect()
+        },
+        (None, None) => {
+            let mut all = bs.list();
+            all.sort();
+            all
+        },
+        _ => usage(),
+    };
+
+    let stdout = std::io::stdout();
+    delta::write_bundle(&hashes, &*bs, stdout.lock()).and_then(|mut w| w.flush()).expect("cannot write bundle");
+}
+
+fn unbundle(args: &[OsString]) {
+    let mut blockdir = None;
+    let mut pack = None;
+
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--blocks") => blockdir = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--pack")   => pack     = Some(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+
+    // the block directory is also where blocks are unpacked to before they go into a pack
+    let blockdir = blockdir.unwrap_or_else(|| usage());
+    std::fs::create_dir_all(&blockdir).expect("cannot create block directory");
+    let mut bs = open_store(Some(blockdir.clone()), pack, None);
+
+    let stdin = std::io::stdin();
+    let n = delta::read_bundle(stdin.lock(), &mut *bs, Path::new(&blockdir)).expect("cannot read bundle");
+    eprintln!("added {} blocks", n);
+}
+
+fn mount(args: &[OsString]) {
+    let mut blockdir = None;
+    let mut pack = None;
+    let mut indexfile = None;
+    let mut remote = None;
+    let mut upper = None;
+    let mut cache_budget = cache::DEFAULT_BUDGET;
+    let mut threads = workers::DEFAULT_THREADS;
+    let mut files = fdpool::DEFAULT_CAPACITY;
+    let mut latency = false;
+    let mut pubkey = None;
+    let mut signature = None;
+    let mut mountpoint = None;
+
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--blocks") => blockdir  = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--pack")   => pack      = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--index")  => indexfile = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--remote") => remote    = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--upper")  => upper     = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--cache")  => {
+                let mb : usize = args.next().and_then(|a| a.to_str().and_then(|a| a.parse().ok())).unwrap_or_else(|| usage());
+                cache_budget = mb * 1024 * 1024;
+            },
+            Some("--latency") => latency = true,
+            Some("--verify")    => pubkey    = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--signature") => signature = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--threads") => {
+                threads = args.next().and_then(|a| a.to_str().and_then(|a| a.parse().ok())).unwrap_or_else(|| usage());
+            },
+            Some("--files") => {
+                files = args.next().and_then(|a| a.to_str().and_then(|a| a.parse().ok())).unwrap_or_else(|| usage());
+            },
+            _ if mountpoint.is_none() => mountpoint = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    let indexfile = indexfile.unwrap_or_else(|| usage());
+    let hi = match pubkey {
+        Some(pubkey) => load_verified(&indexfile, &pubkey, signature),
+        None => load_index(&indexfile),
+    };
+    fdpool::POOL.set_capacity(files);
+    let bs = open_store(blockdir, pack, remote);
+    let mountpoint = mountpoint.unwrap_or_else(|| usage());
+
+    let mut fs = fs::Fuse::new(&hi, &*bs);
+    fs.cache.budget = cache_budget;
+    fs.metrics.latency = latency;
+    if let Some(upper) = upper {
+        std::fs::create_dir_all(&upper).expect("cannot create upper directory");
+        fs.upper = Some(std::path::PathBuf::from(upper));
+    }
+
+    let fuse_args: Vec<&OsStr> = vec![&OsStr::new("-o"), &OsStr::new("auto_unmount")];
+    fs::mount(&fs, threads, &mountpoint, &fuse_args).unwrap();
+}
+
+fn commit(args: &[OsString]) {
+    let mut blockdir = None;
+    let mut pack = None;
+    let mut indexfile = None;
+    let mut upper = None;
+    let mut output = None;
+    let mut keyfile = None;
+
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--blocks")  => blockdir  = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--pack")    => pack      = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--index")   => indexfile = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--upper")   => upper     = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--output")  => output    = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--encrypt") => keyfile   = Some(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+
+    let parent = load_index(indexfile.unwrap_or_else(|| usage()));
+    let upper = upper.unwrap_or_else(|| usage());
+    let output = output.unwrap_or_else(|| usage());
+    let mut bs = open_store(blockdir, pack, None);
+
+    // changed files are encrypted like the rest of the image
+    let mut chunker = serializer::Chunker::new();
+    chunker.secret = keyfile.map(|keyfile| crypto::image_secret(keyfile).expect("cannot read key file"));
+    let hi = commit::commit(&parent, Path::new(&upper), &mut *bs, chunker).expect("commit failed");
+    save_index(&hi, &output);
+}
+
+
+fn keygen(args: &[OsString]) {
+    if args.len() != 1 {
+        usage();
+    }
+    let public = signature::generate(&args[0]).expect("cannot write key");
+    println!("{}", crypto::to_hex(&public));
+}
+
+fn sign(args: &[OsString]) {
+    let mut indexfile = None;
+    let mut keyfile = None;
+    let mut sigfile = None;
+
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--index")     => indexfile = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--key")       => keyfile   = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--signature") => sigfile   = Some(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+
+    let indexfile = indexfile.unwrap_or_else(|| usage());
+    let seed = signature::read_seed(keyfile.unwrap_or_else(|| usage())).expect("cannot read key");
+    let sigfile = sigfile.map(std::path::PathBuf::from).unwrap_or_else(|| signature::signature_path(&indexfile));
+    let hi = load_index(&indexfile);
+    signature::sign_index(&seed, &hi, &sigfile).expect("cannot sign index");
+}
+
+fn verify(args: &[OsString]) {
+    let mut indexfile = None;
+    let mut pubkey = None;
+    let mut sigfile = None;
+
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--index")     => indexfile = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--key")       => pubkey    = Some(args.next().unwrap_or_else(|| usage())),
+            Some("--signature") => sigfile   = Some(args.next().unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+
+    load_verified(&indexfile.unwrap_or_else(|| usage()), &pubkey.unwrap_or_else(|| usage()), sigfile);
+    println!("ok");
+}
+
+
+fn hash(args: &[OsString]) {
+    let mut indexfile = None;
+    let mut path = None;
+
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--index") => indexfile = Some(args.next().unwrap_or_else(|| usage())),
+            Some(p) if path.is_none() => path = Some(String::from(p)),
+            _ => usage(),
+        }
+    }
+
+    let hi = load_index(indexfile.unwrap_or_else(|| usage()));
+    match path {
+        Some(path) => match merkle::subtree(&hi, &path).expect("invalid index") {
+            Some(h) => println!("{}", h),
+            None => {
+                eprintln!("{}: not in the index", path);
+                std::process::exit(1);
+            },
+        },
+        None => println!("{}", merkle::root(&hi).expect("invalid index")),
+    }
 }
 
 
 #[test]
 fn snail() {
-    let mut bs = blockstore::new();
+    let mut bs = blockstore::memory();
     let mut hi = index::from_host(std::ffi::OsString::from("."));
-    hi.serialize(&mut bs);
+    hi.serialize(&mut bs).unwrap();
 
 }
@@ -0,0 +1,155 @@
+use std::ffi::OsStr;
+use std::io::{self, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use sha2::{Sha512, Digest};
+
+use index::Index;
+
+
+fn number(h: &mut Sha512, n: u64) {
+    h.input(&n.to_le_bytes());
+}
+
+fn bytes(h: &mut Sha512, b: &[u8]) {
+    number(h, b.len() as u64);
+    h.input(b);
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// open marks the directories on the way from the root to i. the index isn't trusted yet
+/// when a signature is checked, so entries pointing outside of it or back up are errors.
+fn hash(index: &Index, i: usize, r: &mut Vec<Option<String>>, open: &mut Vec<bool>) -> io::Result<String> {
+    if i >= index.inodes.len() {
+        return Err(invalid(format!("entry for inode {} beyond the end of the index", i)));
+    }
+    if let Some(ref h) = r[i] {
+        return Ok(h.clone());
+    }
+    if open[i] {
+        return Err(invalid(format!("inode {} is its own parent", i)));
+    }
+    open[i] = true;
+    let inode = &index.inodes[i];
+    let mut h = Sha512::default();
+    number(&mut h, inode.k as u64);
+    number(&mut h, inode.a as u64);
+    number(&mut h, inode.u as u64);
+    number(&mut h, inode.g as u64);
+    number(&mut h, inode.s as u64);
+
+    if let Some(ref d) = inode.d {
+        number(&mut h, d.len() as u64);
+        for (name, e) in d {
+            let child = hash(index, e.i as usize, r, open)?;
+            bytes(&mut h, name.as_bytes());
+            bytes(&mut h, child.as_bytes());
+        }
+    }
+    if let Some(ref c) = inode.c {
+        number(&mut h, c.len() as u64);
+        for b in c {
+            bytes(&mut h, b.h.as_bytes());
+            number(&mut h, b.o);
+            number(&mut h, b.l);
+            bytes(&mut h, b.e.as_ref().map(|e| e.as_bytes()).unwrap_or(b""));
+        }
+    }
+    if let Some(ref l) = inode.l {
+        bytes(&mut h, l.as_bytes());
+    }
+
+    let hs = format!("{:x}", h.result());
+    r[i] = Some(hs.clone());
+    open[i] = false;
+    Ok(hs)
+}
+
+/// hash of every node of the index, by position in the index.
+/// a directory's hash covers the names and hashes of its entries, a file's the list of
+/// its blocks, and every hash the metadata of the node itself. positions and host paths
+/// are left out, so the same tree gets the same hashes however it was built.
+pub fn hashes(index: &Index) -> io::Result<Vec<String>> {
+    let mut r = vec![None; index.inodes.len()];
+    hash(index, 0, &mut r, &mut vec![false; index.inodes.len()])?;
+    // nodes which can't be reached from the root don't take part
+    Ok(r.into_iter().map(|h| h.unwrap_or_default()).collect())
+}
+
+/// the single identifier of an image
+pub fn root(index: &Index) -> io::Result<String> {
+    let mut r = vec![None; index.inodes.len()];
+    hash(index, 0, &mut r, &mut vec![false; index.inodes.len()])
+}
+
+/// hash of the subtree at path, if there is one
+pub fn subtree(index: &Index, path: &str) -> io::Result<Option<String>> {
+    let mut hashes = hashes(index)?;
+    let mut i = 0;
+    for name in path.split('/').filter(|n| !n.is_empty() && *n != ".") {
+        i = match index.inodes[i].d.as_ref().and_then(|d| d.get(OsStr::new(name))) {
+            Some(e) => e.i as usize,
+            None => return Ok(None),
+        };
+    }
+    Ok(Some(hashes.swap_remove(i)))
+}
+
+
+#[test]
+fn merkle_hashes() {
+    use std::ffi::OsString;
+    use index::ContentDirEntry;
+
+    let tmp = ::tarball::TempDir::new("merkle");
+    let mut bs = ::blockstore::memory();
+    let a = ::tarball::from_tar(&::tarball::tar_fixture()[..], &mut bs, &tmp).unwrap();
+    let b = ::tarball::from_tar(&::tarball::tar_fixture()[..], &mut bs, &tmp).unwrap();
+    assert_eq!(root(&a).unwrap().len(), 128);
+    assert_eq!(root(&a).unwrap(), root(&b).unwrap());
+
+    // the same tree with its nodes in another order
+    let mut c = Index{
+        inodes: a.inodes.clone(),
+    };
+    let last = c.inodes.len() - 1;
+    c.inodes.swap(1, last);
+    for inode in c.inodes.iter_mut() {
+        if let Some(ref mut d) = inode.d {
+            for e in d.values_mut() {
+                e.i = if e.i == 1 { last as u64 } else if e.i == last as u64 { 1 } else { e.i };
+            }
+        }
+    }
+    assert_eq!(root(&c).unwrap(), root(&a).unwrap());
+
+    // a change shows up in the hashes of all its parents, and only there
+    let before = hashes(&a).unwrap();
+    let etc = a.inodes[0].d.as_ref().unwrap()[OsStr::new("etc")].i as usize;
+    let conf = a.inodes[etc].d.as_ref().unwrap()[OsStr::new("resolv.conf")].i as usize;
+    c = Index{
+        inodes: a.inodes.clone(),
+    };
+    c.inodes[conf].a = 0o600;
+    let after = hashes(&c).unwrap();
+    assert!(after[0] != before[0] && after[etc] != before[etc] && after[conf] != before[conf]);
+    let link = a.inodes[0].d.as_ref().unwrap()[OsStr::new("resolv.conf")].i as usize;
+    assert_eq!(after[link], before[link]);
+    assert_eq!(subtree(&c, "etc/resolv.conf").unwrap(), Some(after[conf].clone()));
+    assert_eq!(subtree(&c, "etc/nothing").unwrap(), None);
+
+    // names are part of the directory hash
+    let e = c.inodes[0].d.as_mut().unwrap().remove(OsStr::new("etc")).unwrap();
+    c.inodes[0].d.as_mut().unwrap().insert(OsString::from("etc2"), ContentDirEntry{i: e.i, k: e.k});
+    assert!(root(&c).unwrap() != after[0]);
+
+    // a malformed index is an error, not a crash
+    c.inodes[0].d.as_mut().unwrap().get_mut(OsStr::new("etc2")).unwrap().i = 100;
+    assert_eq!(root(&c).unwrap_err().kind(), ErrorKind::InvalidData);
+    c.inodes[0].d.as_mut().unwrap().get_mut(OsStr::new("etc2")).unwrap().i = e.i;
+    c.inodes[etc].d.as_mut().unwrap().insert(OsString::from("up"), ContentDirEntry{i: 0, k: 1});
+    assert_eq!(root(&c).unwrap_err().kind(), ErrorKind::InvalidData);
+    assert_eq!(root(&Index{inodes: Vec::new()}).unwrap_err().kind(), ErrorKind::InvalidData);
+}
@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::ptr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use libc;
+
+use cache;
+
+
+/// latency buckets are powers of two in microseconds, the last one takes everything slower
+pub const BUCKETS: usize = 24;
+
+#[derive(Clone, Copy, Default)]
+struct Op {
+    requests: u64,
+    errors:   u64,
+    latency:  [u64; BUCKETS],
+}
+
+/// request counters of a mount, and optionally a latency histogram for every operation
+pub struct Metrics {
+    pub latency: bool,
+    ops:        Mutex<BTreeMap<&'static str, Op>>,
+    bytes_read: AtomicU64,
+}
+
+pub fn new(latency: bool) -> Metrics {
+    Metrics{
+        latency:    latency,
+        ops:        Mutex::new(BTreeMap::new()),
+        bytes_read: AtomicU64::new(0),
+    }
+}
+
+fn bucket(d: Duration) -> usize {
+    let us = d.as_secs() * 1_000_000 + d.subsec_nanos() as u64 / 1000;
+    let b = (64 - us.leading_zeros()) as usize;
+    ::std::cmp::min(b, BUCKETS - 1)
+}
+
+/// upper bound in microseconds of the bucket containing the q-th quantile
+fn quantile(latency: &[u64; BUCKETS], q: f64) -> u64 {
+    let total: u64 = latency.iter().sum();
+    let want = (total as f64 * q).ceil() as u64;
+    let mut seen = 0;
+    for (b, n) in latency.iter().enumerate() {
+        seen += *n;
+        if seen >= want {
+            return 1 << b;
+        }
+    }
+    1 << (BUCKETS - 1)
+}
+
+impl Metrics {
+    /// one request of op, with how long it took if latency is tracked
+    pub fn record(&self, op: &'static str, elapsed: Option<Duration>, ok: bool) {
+        let mut ops = self.ops.lock().unwrap();
+        let o = ops.entry(op).or_insert_with(Op::default);
+        o.requests += 1;
+        if !ok {
+            o.errors += 1;
+        }
+        if let (true, Some(d)) = (self.latency, elapsed) {
+            o.latency[bucket(d)] += 1;
+        }
+    }
+
+    pub fn add_read(&self, bytes: u64) {
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// key=value lines, one per operation and one for the block cache
+    pub fn report(&self, cache: &cache::Stats) -> Vec<String> {
+        let mut r = Vec::new();
+        for (name, o) in self.ops.lock().unwrap().iter() {
+            let mut line = format!("op={} requests={} errors={}", name, o.requests, o.errors);
+            if self.latency {
+                line += &format!(" p50_us={} p90_us={} p99_us={}",
+                                 quantile(&o.latency, 0.5), quantile(&o.latency, 0.9), quantile(&o.latency, 0.99));
+            }
+            r.push(line);
+        }
+        r.push(format!("bytes_read={} blocks_fetched={} cache_hits={} cache_misses={} readahead={} evicted={}",
+                       self.bytes_read.load(Ordering::Relaxed), cache.misses + cache.readahead,
+                       cache.hits, cache.misses, cache.readahead, cache.evictions));
+        r
+    }
+}
+
+
+fn sigusr1() -> libc::sigset_t {
+    unsafe {
+        let mut set = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGUSR1);
+        set
+    }
+}
+
+/// keep SIGUSR1 from interrupting the calling thread and the threads it starts later,
+/// so it is left to on_signal
+pub fn block_signal() {
+    unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, &sigusr1(), ptr::null_mut());
+    }
+}
+
+/// call f whenever the process gets SIGUSR1, until done is set
+pub fn on_signal<F: Fn()>(done: &AtomicBool, f: F) {
+    let set = sigusr1();
+    let timeout = libc::timespec{tv_sec: 1, tv_nsec: 0};
+    while !done.load(Ordering::SeqCst) {
+        if unsafe { libc::sigtimedwait(&set, ptr::null_mut(), &timeout) } == libc::SIGUSR1 {
+            f();
+        }
+    }
+}
+
+
+#[test]
+fn counters_and_latency() {
+    let m = new(true);
+    m.record("read", Some(Duration::from_millis(1)), true);
+    m.record("read", Some(Duration::new(0, 3000)), true);
+    m.record("read", Some(Duration::new(0, 3000)), true);
+    m.record("lookup", Some(Duration::new(0, 0)), false);
+    m.record("lookup", Some(Duration::new(100, 0)), true);
+    m.add_read(4096);
+    m.add_read(10);
+
+    let stats = cache::Stats{hits: 5, misses: 2, readahead: 1, evictions: 0};
+    assert_eq!(m.report(&stats), vec![
+        "op=lookup requests=2 errors=1 p50_us=1 p90_us=8388608 p99_us=8388608",
+        "op=read requests=3 errors=0 p50_us=4 p90_us=1024 p99_us=1024",
+        "bytes_read=4106 blocks_fetched=3 cache_hits=5 cache_misses=2 readahead=1 evicted=0",
+    ]);
+
+    let m = new(false);
+    m.record("read", None, true);
+    assert_eq!(m.report(&stats)[0], "op=read requests=1 errors=0");
+}
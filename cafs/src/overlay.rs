@@ -0,0 +1,141 @@
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, Metadata, Permissions, DirBuilder};
+use std::io::{self, Write, BufWriter};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use fuse::{FileAttr, FileType};
+use libc;
+use time::Timespec;
+
+use blockstore::BlockStore;
+use export::lchown;
+use index::Inode;
+
+
+/// deleted lower entries are marked in the upper directory with an empty file named
+/// .wh.<name>, like aufs and oci layers do
+pub const WHITEOUT_PREFIX: &'static str = ".wh.";
+
+/// a directory containing this hides all lower entries below it
+pub const OPAQUE: &'static str = ".wh..wh..opq";
+
+/// path of a node relative to the root, "" being the root
+pub fn join(dir: &OsStr, name: &OsStr) -> OsString {
+    let mut p = dir.to_os_string();
+    if !dir.is_empty() {
+        p.push("/");
+    }
+    p.push(name);
+    p
+}
+
+pub fn split(path: &OsStr) -> (&OsStr, &OsStr) {
+    let b = path.as_bytes();
+    match b.iter().rposition(|&c| c == b'/') {
+        Some(p) => (OsStr::from_bytes(&b[..p]), OsStr::from_bytes(&b[p+1..])),
+        None    => (OsStr::new(""), path),
+    }
+}
+
+/// the names along path, without empty ones
+pub fn names(path: &OsStr) -> Vec<&OsStr> {
+    path.as_bytes().split(|&c| c == b'/').filter(|n| !n.is_empty()).map(OsStr::from_bytes).collect()
+}
+
+pub fn is_reserved(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(WHITEOUT_PREFIX.as_bytes())
+}
+
+pub fn whiteout_path(upper: &Path, path: &OsStr) -> PathBuf {
+    let (dir, name) = split(path);
+    let mut wh = OsString::from(WHITEOUT_PREFIX);
+    wh.push(name);
+    upper.join(dir).join(wh)
+}
+
+pub fn is_whiteout(upper: &Path, path: &OsStr) -> bool {
+    fs::symlink_metadata(whiteout_path(upper, path)).is_ok()
+}
+
+/// whether an upper directory on the way to path hides the lower one
+pub fn lower_hidden(upper: &Path, path: &OsStr) -> bool {
+    let mut dir = OsString::new();
+    for name in names(path) {
+        if upper.join(&dir).join(OPAQUE).exists() {
+            return true;
+        }
+        dir = join(&dir, name);
+        if is_whiteout(upper, &dir) {
+            return true;
+        }
+    }
+    false
+}
+
+/// like export::DirExport, but for a single inode
+pub fn copy_up(inode: &Inode, blockstore: &BlockStore, target: &Path) -> io::Result<()> {
+    match inode.k {
+        1 => {
+            DirBuilder::new().mode(inode.a as u32).create(target)?;
+        },
+        3 => {
+            symlink(inode.l.as_ref().map(|l| l.as_os_str()).unwrap_or(OsStr::new("")), target)?;
+        },
+        _ => {
+            let tmp = target.with_file_name(".wh..wh..cpy");
+            {
+                let mut f = BufWriter::new(File::create(&tmp)?);
+                if inode.c.is_some() {
+                    io::copy(&mut inode.chain(blockstore), &mut f)?;
+                }
+                f.flush()?;
+            }
+            fs::rename(&tmp, target)?;
+        },
+    }
+    if unsafe { libc::geteuid() } == 0 {
+        lchown(target, inode.u, inode.g)?;
+    }
+    if inode.k != 3 {
+        fs::set_permissions(target, Permissions::from_mode(inode.a as u32))?;
+    }
+    Ok(())
+}
+
+fn timespec(sec: i64, nsec: i64) -> Timespec {
+    Timespec{
+        sec:  sec,
+        nsec: nsec as i32,
+    }
+}
+
+pub fn meta_to_file_attr(ino: u64, meta: &Metadata) -> FileAttr {
+    let ft = meta.file_type();
+    FileAttr {
+        ino: ino,
+        size: meta.len(),
+        blocks: meta.blocks(),
+        atime: timespec(meta.atime(), meta.atime_nsec()),
+        mtime: timespec(meta.mtime(), meta.mtime_nsec()),
+        ctime: timespec(meta.ctime(), meta.ctime_nsec()),
+        crtime: timespec(meta.ctime(), meta.ctime_nsec()),
+        kind: if ft.is_dir() {
+            FileType::Directory
+        } else if ft.is_symlink() {
+            FileType::Symlink
+        } else {
+            FileType::RegularFile
+        },
+        perm: (meta.mode() & 0o7777) as u16,
+        nlink: meta.nlink() as u32,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+pub fn errno(e: io::Error) -> libc::c_int {
+    e.raw_os_error().unwrap_or(libc::EIO)
+}
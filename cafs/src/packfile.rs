@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write, BufRead, BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use blockstore::{Block, BlockShard, BlockStore};
+
+
+/// all blocks appended to a single file, each as a "<hash> <size>\n" header followed by the content
+pub struct PackStore {
+    path:    PathBuf,
+    file:    File,
+    end:     u64,
+    offsets: HashMap<String, (u64, usize)>, // content offset and size of every block
+}
+
+/// open or create a pack. a partially written record at the end, from an interrupted put, is cut off.
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<PackStore> {
+    let path = path.as_ref().to_path_buf();
+    let file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+    let len  = file.metadata()?.len();
+
+    let mut offsets = HashMap::new();
+    let mut end = 0;
+    {
+        let mut r = BufReader::new(&file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let n = r.read_line(&mut line)?;
+            if n < 1 || !line.ends_with('\n') {
+                break;
+            }
+            let mut parts = line.trim_end().splitn(2, ' ');
+            let hash = parts.next().unwrap_or("");
+            let size = match parts.next().and_then(|s| s.parse::<usize>().ok()) {
+                Some(size) if !hash.is_empty() => size,
+                _ => {
+                    return Err(io::Error::new(ErrorKind::InvalidData,
+                                              format!("corrupt record at offset {} in pack {:?}", end, path)));
+                },
+            };
+            let offset = end + n as u64;
+            if offset + size as u64 > len {
+                break;
+            }
+            io::copy(&mut (&mut r).take(size as u64), &mut io::sink())?;
+            offsets.insert(String::from(hash), (offset, size));
+            end = offset + size as u64;
+        }
+    }
+    if end < len {
+        warn!("truncating partial record at the end of pack {:?}", path);
+        file.set_len(end)?;
+    }
+
+    Ok(PackStore{
+        path:    path,
+        file:    file,
+        end:     end,
+        offsets: offsets,
+    })
+}
+
+impl BlockStore for PackStore {
+    fn get(&self, hash: &String) -> io::Result<Block> {
+        match self.offsets.get(hash) {
+            Some(&(offset, size)) => Ok(Block{
+                shards: vec![BlockShard{
+                    file:   self.path.clone().into_os_string(),
+                    offset: offset as usize,
+                    size:   size,
+                    data:   None,
+                }],
+                size: size,
+            }),
+            None => Err(io::Error::new(ErrorKind::NotFound, format!("block {} not found", hash))),
+        }
+    }
+    fn put(&mut self, hash: String, block: Block) -> io::Result<()> {
+        if self.offsets.contains_key(&hash) {
+            return Ok(());
+        }
+        let header = format!("{} {}\n", hash, block.size);
+        let mut record = header.clone().into_bytes();
+        block.chain().read_to_end(&mut record)?;
+        if record.len() != header.len() + block.size {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof,
+                                      format!("block {} is shorter than {} bytes", hash, block.size)));
+        }
+        if let Err(e) = self.file.write_all(&record) {
+            self.file.set_len(self.end)?;
+            return Err(e);
+        }
+        self.offsets.insert(hash, (self.end + header.len() as u64, block.size));
+        self.end += record.len() as u64;
+        Ok(())
+    }
+    fn contains(&self, hash: &String) -> bool {
+        self.offsets.contains_key(hash)
+    }
+    fn list(&self) -> Vec<String> {
+        self.offsets.keys().cloned().collect()
+    }
+    fn size(&self, hash: &String) -> Option<usize> {
+        self.offsets.get(hash).map(|&(_, size)| size)
+    }
+}
+
+
+#[test]
+fn pack_reopen_and_truncate() {
+    use std::fs;
+    use blockstore::test_block;
+
+    let tmp = ::tarball::TempDir::new("pack");
+    let path = tmp.join("pack");
+    {
+        let mut pack = open(&path).unwrap();
+        pack.put(String::from("aa"), test_block(vec![("test/readchain/a", 0, 4)])).unwrap();
+        pack.put(String::from("bb"), test_block(vec![("test/readchain/b", 0, 4)])).unwrap();
+        pack.put(String::from("aa"), test_block(vec![("test/readchain/a", 0, 4)])).unwrap();
+    }
+    let len = fs::metadata(&path).unwrap().len();
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(b"cc 100\nshort").unwrap();
+
+    let pack = open(&path).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    assert_eq!(pack.list().len(), 2);
+    assert!(!pack.contains(&String::from("cc")));
+
+    let mut content = String::new();
+    pack.get(&String::from("bb")).unwrap().chain().read_to_string(&mut content).unwrap();
+    assert_eq!(content, "cool");
+}
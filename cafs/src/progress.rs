@@ -0,0 +1,220 @@
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{self, Stdout, Write};
+use std::time::{Duration, Instant};
+use pbr::{ProgressBar, Units};
+
+
+/// how far a build got
+#[derive(Clone, Debug, Default)]
+pub struct Status {
+    pub files:  u64, // files started
+    pub bytes:  u64, // content read
+    pub blocks: u64, // blocks emitted
+    pub dedup:  u64, // blocks emitted which the store already had
+
+    // when they are known up front
+    pub files_total: Option<u64>,
+    pub bytes_total: Option<u64>,
+
+    pub elapsed: Duration,
+}
+
+impl Status {
+    /// time left, going by the bytes read so far
+    pub fn eta(&self) -> Option<Duration> {
+        let total = self.bytes_total?;
+        if self.bytes == 0 {
+            return None;
+        }
+        let left = total.saturating_sub(self.bytes) as f64 / self.bytes as f64;
+        Some(Duration::from_secs_f64(self.elapsed.as_secs_f64() * left))
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "done serializing {} files with {} bytes to {} blocks, {} of them already stored",
+               self.files, self.bytes, self.blocks, self.dedup)
+    }
+}
+
+
+/// told about a build while it runs. nothing is done by default, so an empty impl is silent.
+pub trait Progress {
+    /// a file is about to be chunked
+    fn file(&mut self, _path: &OsStr, _status: &Status) {}
+
+    /// a block was emitted
+    fn block(&mut self, _status: &Status) {}
+
+    fn finish(&mut self, _status: &Status) {}
+}
+
+pub struct Silent;
+
+impl Progress for Silent {}
+
+
+/// a bar on the terminal and a summary when done. the bar goes by bytes, so it needs the
+/// size of all content. without it, like for a stream or a tar, the counts are shown instead.
+pub struct Bar {
+    bar:     Option<ProgressBar<Stdout>>,
+    counted: Option<Instant>, // when the counts were last shown
+}
+
+pub fn bar() -> Bar {
+    Bar{
+        bar:     None,
+        counted: None,
+    }
+}
+
+fn path_message(path: &OsStr) -> String {
+    let s = path.to_string_lossy();
+    let n = s.chars().count();
+    if n > 40 {
+        format!("..{:38} ", s.chars().skip(n - 38).collect::<String>())
+    } else {
+        format!("{:40} ", s)
+    }
+}
+
+impl Bar {
+    fn draw(&mut self, status: &Status) {
+        if let (&None, Some(total)) = (&self.bar, status.bytes_total) {
+            let mut bar = ProgressBar::new(total);
+            bar.set_units(Units::Bytes);
+            bar.show_speed = false;
+            self.bar = Some(bar);
+        }
+        if let Some(ref mut bar) = self.bar {
+            bar.set(status.bytes);
+        } else if self.counted.map_or(true, |t| t.elapsed() >= Duration::from_secs(1)) {
+            self.counted = Some(Instant::now());
+            self.count(status);
+        }
+    }
+
+    fn count(&self, status: &Status) {
+        let mut out = io::stdout();
+        let _ = write!(out, "\r{} files, {} bytes, {} blocks", status.files, status.bytes, status.blocks)
+            .and_then(|_| out.flush());
+    }
+}
+
+impl Progress for Bar {
+    fn file(&mut self, path: &OsStr, status: &Status) {
+        self.draw(status);
+        if let Some(ref mut bar) = self.bar {
+            bar.message(&path_message(path));
+        }
+    }
+
+    fn block(&mut self, status: &Status) {
+        self.draw(status);
+    }
+
+    fn finish(&mut self, status: &Status) {
+        if let Some(ref mut bar) = self.bar {
+            bar.finish_print("");
+        } else if self.counted.is_some() {
+            self.count(status);
+            println!();
+        }
+        println!("{}", status);
+    }
+}
+
+
+/// a json object per line, at most one a second while running and one when done
+pub struct JsonLines<W: Write> {
+    out:  W,
+    last: Option<Instant>,
+}
+
+pub fn json_lines<W: Write>(out: W) -> JsonLines<W> {
+    JsonLines{
+        out:  out,
+        last: None,
+    }
+}
+
+impl<W: Write> JsonLines<W> {
+    fn line(&mut self, done: bool, status: &Status) {
+        if !done && self.last.map_or(false, |t| t.elapsed() < Duration::from_secs(1)) {
+            return;
+        }
+        self.last = Some(Instant::now());
+        let line = json!({
+            "done":        done,
+            "files":       status.files,
+            "files_total": status.files_total,
+            "bytes":       status.bytes,
+            "bytes_total": status.bytes_total,
+            "blocks":      status.blocks,
+            "dedup":       status.dedup,
+            "eta":         status.eta().map(|d| d.as_secs()),
+        });
+        // progress is no reason to fail a build
+        let _ = writeln!(self.out, "{}", line).and_then(|_| self.out.flush());
+    }
+}
+
+impl<W: Write> Progress for JsonLines<W> {
+    fn file(&mut self, _path: &OsStr, status: &Status) {
+        self.line(false, status);
+    }
+
+    fn block(&mut self, status: &Status) {
+        self.line(false, status);
+    }
+
+    fn finish(&mut self, status: &Status) {
+        self.line(true, status);
+    }
+}
+
+
+#[test]
+fn reported_progress() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use serializer::Chunker;
+
+    struct Record(Rc<RefCell<Vec<Status>>>);
+    impl Progress for Record {
+        fn block(&mut self, status: &Status) {
+            self.0.borrow_mut().push(status.clone());
+        }
+        fn finish(&mut self, status: &Status) {
+            self.0.borrow_mut().push(status.clone());
+        }
+    }
+
+    let mut bs = ::blockstore::memory();
+    let mut last = Status::default();
+    for round in 0..2 {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut index = ::index::from_host(::std::ffi::OsString::from("test/image_same"));
+        let mut chunker = Chunker::new();
+        chunker.progress = Box::new(Record(seen.clone()));
+        index.serialize_with(&mut bs, chunker).unwrap();
+
+        let seen = seen.borrow();
+        assert!(seen.windows(2).all(|w| w[0].bytes <= w[1].bytes && w[0].blocks <= w[1].blocks));
+        last = seen.last().unwrap().clone();
+        assert_eq!(Some(last.files), last.files_total);
+        assert_eq!(Some(last.bytes), last.bytes_total);
+        // the second time, every block is already there
+        assert_eq!(last.dedup, if round == 0 { last.blocks - bs.blocks.len() as u64 } else { last.blocks });
+    }
+    assert_eq!(last.eta(), Some(Duration::from_secs(0)));
+
+    let mut out = Vec::new();
+    json_lines(&mut out).finish(&last);
+    let line : ::serde_json::Value = ::serde_json::from_slice(&out).unwrap();
+    assert_eq!(line["done"], true);
+    assert_eq!(line["bytes"], last.bytes);
+    assert_eq!(line["eta"], 0);
+}
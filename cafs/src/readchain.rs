@@ -45,6 +45,35 @@ impl<R> Seek for Take<R> where R: Read+Seek {
 }
 
 
+/// a reader which may have failed to open, failing every read and seek instead.
+/// lets a Chain open its readers only when it gets to them and still report errors.
+pub struct Fallible<R>(pub Result<R>);
+
+impl<R> Fallible<R> {
+    fn failed(e: &Error) -> Error {
+        Error::new(e.kind(), e.to_string())
+    }
+}
+
+impl<R> Read for Fallible<R> where R: Read {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self.0 {
+            Ok(ref mut r) => r.read(buf),
+            Err(ref e) => Err(Self::failed(e)),
+        }
+    }
+}
+
+impl<R> Seek for Fallible<R> where R: Seek {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        match self.0 {
+            Ok(ref mut r) => r.seek(pos),
+            Err(ref e) => Err(Self::failed(e)),
+        }
+    }
+}
+
+
 /// like std::io::Chain but on an Iterator which may contain a lambda and with Seek
 pub struct Chain<'a, R> where R : Read {
     it: Box<Iterator<Item=R> + 'a>,
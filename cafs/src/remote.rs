@@ -0,0 +1,303 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write, BufRead, BufReader, ErrorKind};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use sha2::{Sha512, Digest};
+
+use blockstore::{Block, BlockShard, BlockStore};
+
+
+// downloads so far, so that concurrent downloads of the same block don't share a temp file
+static DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+
+
+/// fetches blocks from a static http server as GET <base>/blocks/<hash>
+/// and keeps them as loose blocks in a local cache directory
+pub struct HttpStore {
+    host:  String,
+    port:  u16,
+    path:  String,
+    cache: PathBuf,
+
+    pub retries: u32,
+    pub timeout: Duration,
+}
+
+pub fn new<P: AsRef<Path>>(url: &str, cache: P) -> io::Result<HttpStore> {
+    let rest = if url.starts_with("http://") {
+        &url[7..]
+    } else {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("unsupported url {}, only http:// is supported", url)));
+    };
+    let (hostport, path) = match rest.find('/') {
+        Some(p) => (&rest[..p], rest[p..].trim_end_matches('/')),
+        None    => (rest, ""),
+    };
+    let (host, port) = match hostport.rfind(':') {
+        Some(p) => {
+            let port = hostport[p+1..].parse().map_err(|_| {
+                io::Error::new(ErrorKind::InvalidInput, format!("invalid port in url {}", url))
+            })?;
+            (&hostport[..p], port)
+        },
+        None => (hostport, 80),
+    };
+
+    Ok(HttpStore{
+        host:  String::from(host),
+        port:  port,
+        path:  String::from(path),
+        cache: cache.as_ref().to_path_buf(),
+
+        retries: 3,
+        timeout: Duration::from_secs(30),
+    })
+}
+
+impl HttpStore {
+    /// the block from the local cache, downloading it first if necessary
+    pub fn fetch(&self, hash: &String) -> io::Result<Block> {
+        let path = self.cache.join(hash);
+        if !path.exists() {
+            self.download(hash, &path)?;
+        }
+        let size = fs::metadata(&path)?.len() as usize;
+        Ok(Block{
+            shards: vec![BlockShard{
+                file:   path.into_os_string(),
+                offset: 0,
+                size:   size,
+                data:   None,
+            }],
+            size: size,
+        })
+    }
+
+    fn download(&self, hash: &String, path: &Path) -> io::Result<()> {
+        if hash.is_empty() || !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("invalid block hash {:?}", hash)));
+        }
+
+        let mut attempt = 0;
+        let content = loop {
+            let err = match self.get(&format!("{}/blocks/{}", self.path, hash)) {
+                Ok(content) => {
+                    if format!("{:x}", Sha512::digest(&content)) == *hash {
+                        break content;
+                    }
+                    io::Error::new(ErrorKind::InvalidData, format!("block {} from server is corrupt", hash))
+                },
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                    return Err(io::Error::new(ErrorKind::NotFound, format!("block {} not found on server", hash)));
+                },
+                Err(e) => e,
+            };
+            attempt += 1;
+            if attempt > self.retries {
+                return Err(err);
+            }
+            thread::sleep(Duration::from_millis(100 << attempt));
+        };
+
+        let n = DOWNLOADS.fetch_add(1, Ordering::Relaxed);
+        let tmp = self.cache.join(format!("{}.{}.{}.tmp", hash, process::id(), n));
+        File::create(&tmp)?.write_all(&content)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// size of a block on the server, without downloading it
+    fn head(&self, hash: &String) -> io::Result<Option<u64>> {
+        self.request("HEAD", &format!("{}/blocks/{}", self.path, hash)).map(|(length, _)| length)
+    }
+
+    fn get(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.request("GET", path).map(|(_, body)| body)
+    }
+
+    /// a single http/1.1 request, returning the content-length header and the body
+    fn request(&self, method: &str, path: &str) -> io::Result<(Option<u64>, Vec<u8>)> {
+        let mut s = TcpStream::connect((self.host.as_str(), self.port))?;
+        s.set_read_timeout(Some(self.timeout))?;
+        s.set_write_timeout(Some(self.timeout))?;
+        write!(s, "{} {} HTTP/1.1\r\nHost: {}:{}\r\nConnection: close\r\n\r\n", method, path, self.host, self.port)?;
+
+        let mut r = BufReader::new(s);
+        let mut line = String::new();
+        r.read_line(&mut line)?;
+        let status : u32 = line.split_whitespace().nth(1).and_then(|s| s.parse().ok()).ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidData, format!("invalid http status line {:?}", line))
+        })?;
+
+        let mut length  = None;
+        let mut chunked = false;
+        loop {
+            line.clear();
+            if r.read_line(&mut line)? < 1 {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed in http header"));
+            }
+            let l = line.trim_end();
+            if l.is_empty() {
+                break;
+            }
+            if let Some(p) = l.find(':') {
+                let name  = l[..p].trim().to_lowercase();
+                let value = l[p+1..].trim();
+                if name == "content-length" {
+                    length = value.parse::<u64>().ok();
+                } else if name == "transfer-encoding" && value.to_lowercase().contains("chunked") {
+                    chunked = true;
+                }
+            }
+        }
+
+        match status {
+            200 => {},
+            404 => return Err(io::Error::new(ErrorKind::NotFound, format!("{} {}: not found", method, path))),
+            _   => return Err(io::Error::new(ErrorKind::Other, format!("{} {}: http status {}", method, path, status))),
+        }
+
+        let mut body = Vec::new();
+        if method == "HEAD" {
+            return Ok((length, body));
+        }
+        if chunked {
+            loop {
+                line.clear();
+                r.read_line(&mut line)?;
+                let size = line.trim().split(';').next().and_then(|s| u64::from_str_radix(s, 16).ok()).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidData, "invalid chunk size")
+                })?;
+                if size == 0 {
+                    break;
+                }
+                if (&mut r).take(size).read_to_end(&mut body)? as u64 != size {
+                    return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed in http body"));
+                }
+                line.clear();
+                r.read_line(&mut line)?;
+            }
+        } else if let Some(length) = length {
+            if r.take(length).read_to_end(&mut body)? as u64 != length {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed in http body"));
+            }
+        } else {
+            r.read_to_end(&mut body)?;
+        }
+        Ok((length, body))
+    }
+}
+
+/// a read only store, blocks are fetched on get
+impl BlockStore for HttpStore {
+    fn get(&self, hash: &String) -> io::Result<Block> {
+        self.fetch(hash)
+    }
+    fn put(&mut self, hash: String, _block: Block) -> io::Result<()> {
+        Err(io::Error::new(ErrorKind::PermissionDenied, format!("cannot store block {} on a http server", hash)))
+    }
+    fn contains(&self, hash: &String) -> bool {
+        self.cache.join(hash).exists() || self.head(hash).is_ok()
+    }
+    fn list(&self) -> Vec<String> {
+        // there is no listing on a static server, only what was fetched so far is known
+        match fs::read_dir(&self.cache) {
+            Ok(entries) => entries.filter_map(|e| e.ok())
+                .filter_map(|e| e.file_name().into_string().ok())
+                .filter(|name| !name.is_empty() && name.bytes().all(|c| c.is_ascii_hexdigit()))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+    fn size(&self, hash: &String) -> Option<usize> {
+        match fs::metadata(self.cache.join(hash)) {
+            Ok(m) => Some(m.len() as usize),
+            Err(_) => self.head(hash).ok().and_then(|l| l).map(|l| l as usize),
+        }
+    }
+}
+
+
+/// serves canned responses for the given paths, one connection at a time
+#[cfg(test)]
+pub fn test_server(responses: Vec<(String, u32, Vec<u8>)>) -> (u16, thread::JoinHandle<()>) {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let t = thread::spawn(move || {
+        for (path, status, body) in responses {
+            let (s, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(s.try_clone().unwrap());
+            let mut request = String::new();
+            r.read_line(&mut request).unwrap();
+            assert!(request.ends_with(&format!(" {} HTTP/1.1\r\n", path)));
+            let mut line = String::new();
+            loop {
+                line.clear();
+                r.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let head = request.starts_with("HEAD ");
+            let mut s = s;
+            write!(s, "HTTP/1.1 {} whatever\r\nContent-Length: {}\r\n\r\n", status, body.len()).unwrap();
+            if !head {
+                s.write_all(&body).unwrap();
+            }
+        }
+    });
+    (port, t)
+}
+
+#[test]
+fn fetch_with_retry_and_verify() {
+    let cache = ::tarball::TempDir::new("remote");
+
+    let content = b"cool stuff".to_vec();
+    let hash = format!("{:x}", Sha512::digest(&content));
+    let path = format!("/img/blocks/{}", hash);
+
+    let (port, server) = test_server(vec![
+        (path.clone(), 503, Vec::new()),
+        (path.clone(), 200, b"corrupt".to_vec()),
+        (path.clone(), 200, content.clone()),
+    ]);
+
+    let store = new(&format!("http://127.0.0.1:{}/img/", port), &cache).unwrap();
+    let block = store.fetch(&hash).unwrap();
+    server.join().unwrap();
+
+    let mut r = Vec::new();
+    block.chain().read_to_end(&mut r).unwrap();
+    assert_eq!(r, content);
+
+    // cached now, the server is gone
+    let block = store.fetch(&hash).unwrap();
+    assert_eq!(block.size, content.len());
+}
+
+#[test]
+fn fetch_not_found() {
+    let cache = ::tarball::TempDir::new("remote404");
+
+    let (port, server) = test_server(vec![
+        (String::from("/blocks/abcd"), 404, Vec::new()),
+    ]);
+    let store = new(&format!("http://127.0.0.1:{}", port), &cache).unwrap();
+    assert_eq!(store.fetch(&String::from("abcd")).err().unwrap().kind(), ErrorKind::NotFound);
+    server.join().unwrap();
+
+    let (port, server) = test_server(vec![
+        (String::from("/blocks/abcd"), 404, Vec::new()),
+        (String::from("/blocks/ef01"), 200, b"1234".to_vec()),
+    ]);
+    let store = new(&format!("http://127.0.0.1:{}", port), &cache).unwrap();
+    assert!(!store.contains(&String::from("abcd")));
+    assert_eq!(store.size(&String::from("ef01")), Some(4));
+    server.join().unwrap();
+}
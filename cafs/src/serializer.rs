@@ -1,13 +1,16 @@
 use std::fs::File;
-use std::io::{Read, BufReader};
+use std::io::{self, Read, BufReader};
 use rollsum;
 
 use sha2::{Sha512, Digest};
 use index::*;
-use blockstore::{Block, BlockStore, BlockShard};
-use pbr::ProgressBar;
+use blockstore::{self, Block, BlockStore, BlockShard, CollisionPolicy};
+use crypto::{self, Key};
+use progress::{Progress, Silent, Status};
 use std::ffi::OsString;
-use std::io::Stdout;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
 
 
 struct IntermediateBlockRef {
@@ -18,126 +21,257 @@ struct IntermediateBlockRef {
 }
 
 
-fn print_progress_bar(bar: &mut ProgressBar<Stdout>, path: &OsString){
-    let mut s = path.to_str().unwrap();
-    if s.len() > 40 {
-        bar.message(&format!("..{:38} ", &s[s.len()-38..]));
-    } else {
-        bar.message(&format!("{:40} ", &s));
+/// where the chunker finds the files it was given, and puts their block entries
+pub trait Content {
+    fn host_path(&self, inode: u64) -> &OsString;
+    fn add_block(&mut self, inode: u64, block: ContentBlockEntry);
+    fn set_hash(&mut self, inode: u64, hash: String);
+}
+
+impl Content for Index {
+    fn host_path(&self, inode: u64) -> &OsString {
+        &self.inodes[inode as usize].host_path
+    }
+
+    fn add_block(&mut self, inode: u64, block: ContentBlockEntry) {
+        self.inodes[inode as usize].c.get_or_insert(Vec::new()).push(block);
+    }
+
+    fn set_hash(&mut self, inode: u64, hash: String) {
+        self.inodes[inode as usize].h = Some(hash);
     }
 }
 
-impl Index {
-    fn emit_block(&mut self, blockstore: &mut BlockStore, len: usize, hash: String, inodes: &Vec<IntermediateBlockRef>) {
+/// cuts a stream of files into content defined blocks
+pub struct Chunker<'a> {
+    chunker: rollsum::Bup,
+    hasher:  Sha512,
 
-        let mut block_shards = Vec::new();
-        //println!("block {}", hash);
-        for ibr in inodes {
-            //println!("   inode {} at offset {} is {} into the block with size {}",
-            //         ibr.inode, ibr.file_start, ibr.block_start, ibr.file_end - ibr.file_start);
-            block_shards.push(BlockShard{
-                file:    self.inodes[ibr.inode as usize].host_path.clone(),
-                offset:  ibr.file_start,
-                size:    ibr.file_end - ibr.file_start,
-            });
+    current_block_len:      usize,
+    current_files_in_block: Vec<IntermediateBlockRef>,
+    current_file_pos:       usize,
 
-            if let None = self.inodes[ibr.inode as usize].c {
-                self.inodes[ibr.inode  as usize].c = Some(Vec::new());
-            }
-            self.inodes[ibr.inode as usize].c.as_mut().unwrap().push(ContentBlockEntry{
-                h: hash.clone(),
-                o: ibr.block_start as u64,
-                l: (ibr.file_end - ibr.file_start) as u64,
-            });
+    // for sources that can't be reopened later, block content is
+    // written to a file named by its hash in the given directory
+    loose:   Option<&'a Path>,
+    content: Vec<u8>, // of the current block, when it is needed after the block ended
+
+    pub collision_policy: CollisionPolicy,
+
+    // blocks are encrypted with keys derived from this and their content
+    pub secret: Option<Key>,
+
+    // told about every file and block, silent unless set
+    pub progress: Box<Progress + 'a>,
+    status:       Status,
+    started:      Instant,
+}
+
+impl<'a> Chunker<'a> {
+    /// blocks will reference the inodes host_path
+    pub fn new() -> Chunker<'a> {
+        Chunker {
+            chunker: rollsum::Bup::new_with_chunk_bits(13),
+            hasher:  Sha512::default(),
+
+            current_block_len:      0,
+            current_files_in_block: Vec::new(),
+            current_file_pos:       0,
+
+            loose:   None,
+            content: Vec::new(),
+
+            collision_policy: CollisionPolicy::Panic,
+
+            secret: None,
+
+            progress: Box::new(Silent),
+            status:   Status::default(),
+            started:  Instant::now(),
         }
+    }
 
-        blockstore.insert(hash, Block{
-            shards: block_shards,
-            size: len,
-        });
+    /// how many files and bytes will be added, for progress reports
+    pub fn expect(&mut self, files: u64, bytes: u64) {
+        self.status.files_total = Some(files);
+        self.status.bytes_total = Some(bytes);
     }
 
-    pub fn serialize(&mut self, blockstore: &mut BlockStore) {
-        let mut bar = ProgressBar::new(self.inodes.len() as u64);
-        bar.show_speed = false;
-        bar.show_time_left = false;
+    /// blocks will be stored as files in dir
+    pub fn loose(dir: &'a Path) -> Chunker<'a> {
+        let mut c = Chunker::new();
+        c.loose = Some(dir);
+        c
+    }
 
-        let mut chunker = rollsum::Bup::new_with_chunk_bits(13);
-        let mut hasher  = Sha512::default();
+    fn input(&mut self, buf: &[u8]) {
+        self.hasher.input(buf);
+        if self.loose.is_some() || self.secret.is_some() {
+            self.content.extend_from_slice(buf);
+        }
+    }
 
-        let mut current_block_len = 0;
-        let mut current_files_in_block = Vec::new();
-        let mut current_file_pos = 0;
+    /// append the content of an inode
+    pub fn add<R: Read>(&mut self, index: &mut Content, blockstore: &mut BlockStore, inode: u64, mut file: R) -> io::Result<()> {
+        self.current_files_in_block.push(IntermediateBlockRef{
+            inode: inode,
+            file_start: 0,
+            file_end:   0,
+            block_start: self.current_block_len,
+        });
+        self.status.files += 1;
+        self.status.elapsed = self.started.elapsed();
+        self.progress.file(index.host_path(inode), &self.status);
 
-        let inodes = self.inodes.to_vec();
-        for inode in inodes {
-            bar.inc();
-            if inode.k != 2 {
-                continue;
+        // blocks can span files, so a file also gets a hash of its own content
+        let mut file_hasher = Sha512::default();
+        let mut buf = [0;1024];
+        loop {
+            let rs = file.read(&mut buf)?;
+            if rs < 1 {
+                break;
             }
-            print_progress_bar(&mut bar, &inode.host_path);
+            file_hasher.input(&buf[..rs]);
+            self.status.bytes += rs as u64;
+            let mut restart = 0;
 
+            loop {
+                if let Some(count) = self.chunker.find_chunk_edge(&buf[restart..rs]) {
+                    self.current_block_len += count;
+                    self.current_file_pos  += count;
 
-            let mut file = BufReader::new(File::open(&inode.host_path).unwrap());
-            current_files_in_block.push(IntermediateBlockRef{
-                inode: inode.i,
-                file_start: 0,
-                file_end:   0,
-                block_start: current_block_len,
-            });
+                    self.current_files_in_block.last_mut().as_mut().unwrap().file_end = self.current_file_pos;
 
-            let mut buf = [0;1024];
-            loop {
-                let rs = file.read(&mut buf).unwrap();
-                if rs < 1 {
+                    self.input(&buf[restart..restart+count]);
+                    self.emit_block(index, blockstore)?;
+                    self.current_files_in_block.push(IntermediateBlockRef{
+                        inode: inode,
+                        file_start: self.current_file_pos,
+                        file_end:   0,
+                        block_start: 0,
+                    });
+
+                    restart += count;
+                } else {
                     break;
                 }
-                let mut restart = 0;
-
-                loop {
-                    if let Some(count) = chunker.find_chunk_edge(&buf[restart..rs]) {
-                        current_block_len += count;
-                        current_file_pos  += count;
-
-                        current_files_in_block.last_mut().as_mut().unwrap().file_end = current_file_pos;
-
-                        hasher.input(&buf[restart..restart+count]);
-                        let hash = format!("{:x}", hasher.result());
-                        hasher  = Sha512::default();
-
-                        self.emit_block(blockstore, current_block_len, hash, &current_files_in_block);
-                        current_files_in_block.clear();
-                        current_files_in_block.push(IntermediateBlockRef{
-                            inode: inode.i,
-                            file_start: current_file_pos,
-                            file_end:   0,
-                            block_start: 0,
-                        });
-
-                        current_block_len  = 0;
-                        restart += count;
-                    } else {
-                        break;
-                    }
-                }
-                hasher.input(&buf[restart..rs]);
-                current_block_len += rs - restart;
-                current_file_pos  += rs - restart;
             }
-            current_files_in_block.last_mut().as_mut().unwrap().file_end = current_file_pos;
-            current_file_pos = 0;
+            self.input(&buf[restart..rs]);
+            self.current_block_len += rs - restart;
+            self.current_file_pos  += rs - restart;
         }
-        let hash = format!("{:x}", hasher.result());
-        self.emit_block(blockstore, current_block_len, hash, &current_files_in_block);
+        self.current_files_in_block.last_mut().as_mut().unwrap().file_end = self.current_file_pos;
+        self.current_file_pos = 0;
+        index.set_hash(inode, format!("{:x}", file_hasher.result()));
+        Ok(())
+    }
+
+    /// the first inode the current block will still add entries to.
+    /// inodes before it have all their blocks.
+    pub fn unfinished(&self) -> Option<u64> {
+        self.current_files_in_block.first().map(|r| r.inode)
+    }
+
+    /// emit the last partial block
+    pub fn finish(mut self, index: &mut Content, blockstore: &mut BlockStore) -> io::Result<()> {
+        self.emit_block(index, blockstore)?;
+        self.status.elapsed = self.started.elapsed();
+        self.progress.finish(&self.status);
+        Ok(())
+    }
+
+    fn emit_block(&mut self, index: &mut Content, blockstore: &mut BlockStore) -> io::Result<()> {
+        let hasher = ::std::mem::replace(&mut self.hasher, Sha512::default());
+        let hash   = format!("{:x}", hasher.result());
+        let len    = self.current_block_len;
 
-        let total_block_size = blockstore.blocks.iter().fold(0, |acc, (_,b)| acc + b.size);
-        let total_inode_size = self.inodes.iter().fold(0, |acc, i| acc + i.s);
-        bar.finish_print("");
+        // an encrypted block is stored under the hash of its ciphertext
+        let key = self.secret.as_ref().map(|s| crypto::block_key(s, &hash));
+        let id = match key {
+            Some(ref key) => {
+                crypto::encrypt_block(key, &mut self.content);
+                format!("{:x}", Sha512::digest(&self.content))
+            },
+            None => hash,
+        };
 
+        let mut block_shards = Vec::new();
+        //println!("block {}", id);
+        for ibr in &self.current_files_in_block {
+            //println!("   inode {} at offset {} is {} into the block with size {}",
+            //         ibr.inode, ibr.file_start, ibr.block_start, ibr.file_end - ibr.file_start);
+            if self.loose.is_none() && key.is_none() {
+                block_shards.push(BlockShard{
+                    file:    index.host_path(ibr.inode).clone(),
+                    offset:  ibr.file_start,
+                    size:    ibr.file_end - ibr.file_start,
+                    data:    None,
+                });
+            }
 
-        let pc = (total_block_size as f32 / total_inode_size as f32) * 100.0;
-        println!("done serializing {} inodes to {} blocks with total size of {} bytes ({:.0}% of inodes size)",
-                 self.inodes.len(), blockstore.blocks.len(), total_block_size, pc);
+            index.add_block(ibr.inode, ContentBlockEntry{
+                h: id.clone(),
+                o: ibr.block_start as u64,
+                l: (ibr.file_end - ibr.file_start) as u64,
+                e: key.as_ref().map(|k| crypto::to_hex(k)),
+            });
+        }
+        self.current_files_in_block.clear();
+        self.current_block_len = 0;
 
+        if let Some(dir) = self.loose {
+            let path = dir.join(&id);
+            if !path.exists() {
+                let tmp = dir.join(format!("{}.tmp", id));
+                File::create(&tmp)?.write_all(&self.content)?;
+                ::std::fs::rename(&tmp, &path)?;
+            }
+            self.content.clear();
+            block_shards.push(BlockShard{
+                file:    path.into_os_string(),
+                offset:  0,
+                size:    len,
+                data:    None,
+            });
+        }
+
+        let block = if self.loose.is_none() && key.is_some() {
+            Block::memory(::std::mem::replace(&mut self.content, Vec::new()))
+        } else {
+            Block{
+                shards: block_shards,
+                size: len,
+            }
+        };
+
+        let known = blockstore.contains(&id);
+        blockstore::insert(blockstore, id, block, self.collision_policy)?;
+        self.status.blocks += 1;
+        if known {
+            self.status.dedup += 1;
+        }
+        self.status.elapsed = self.started.elapsed();
+        self.progress.block(&self.status);
+        Ok(())
+    }
+}
+
+impl Index {
+    pub fn serialize(&mut self, blockstore: &mut BlockStore) -> io::Result<()> {
+        self.serialize_with(blockstore, Chunker::new())
+    }
+
+    pub fn serialize_with(&mut self, blockstore: &mut BlockStore, mut chunker: Chunker) -> io::Result<()> {
+        let files = self.inodes.iter().filter(|i| i.k == 2);
+        chunker.expect(files.clone().count() as u64, files.fold(0, |acc, i| acc + i.s));
+
+        for x in 0..self.inodes.len() {
+            if self.inodes[x].k != 2 {
+                continue;
+            }
+            let file = BufReader::new(File::open(&self.inodes[x].host_path)?);
+            chunker.add(self, blockstore, x as u64, file)?;
+        }
+        chunker.finish(self, blockstore)
     }
 }
@@ -0,0 +1,110 @@
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write, ErrorKind};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crypto;
+use ed25519::{self, Seed, PublicKey, Signature};
+use index::Index;
+use merkle;
+
+
+/// signed along with the root hash, so a signature can't be taken for one of something else
+const CONTEXT: &'static str = "cafs index root ";
+
+/// indexes are signed by their merkle root, which covers the whole tree
+pub fn sign(seed: &Seed, root: &str) -> Signature {
+    ed25519::sign(seed, format!("{}{}", CONTEXT, root).as_bytes())
+}
+
+pub fn verify(public: &PublicKey, root: &str, signature: &Signature) -> io::Result<()> {
+    if ed25519::verify(public, format!("{}{}", CONTEXT, root).as_bytes(), signature) {
+        Ok(())
+    } else {
+        Err(io::Error::new(ErrorKind::InvalidData, "index signature does not match"))
+    }
+}
+
+/// where the detached signature of an index file is kept by default
+pub fn signature_path<P: AsRef<Path>>(index: P) -> PathBuf {
+    let mut p = OsString::from(index.as_ref());
+    p.push(".sig");
+    PathBuf::from(p)
+}
+
+fn read_hex<P: AsRef<Path>>(path: P, out: &mut [u8]) -> io::Result<()> {
+    let mut s = String::new();
+    File::open(path)?.read_to_string(&mut s)?;
+    let b = crypto::from_hex(s.trim())?;
+    if b.len() != out.len() {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("expected {} hex digits", out.len() * 2)));
+    }
+    out.copy_from_slice(&b);
+    Ok(())
+}
+
+fn write_hex<P: AsRef<Path>>(path: P, b: &[u8], mode: u32) -> io::Result<()> {
+    let mut f = OpenOptions::new().write(true).create(true).truncate(true).mode(mode).open(path)?;
+    writeln!(f, "{}", crypto::to_hex(b))
+}
+
+pub fn read_seed<P: AsRef<Path>>(path: P) -> io::Result<Seed> {
+    let mut k = [0; 32];
+    read_hex(path, &mut k)?;
+    Ok(k)
+}
+
+pub fn read_public<P: AsRef<Path>>(path: P) -> io::Result<PublicKey> {
+    let mut k = [0; 32];
+    read_hex(path, &mut k)?;
+    Ok(k)
+}
+
+pub fn read_signature<P: AsRef<Path>>(path: P) -> io::Result<Signature> {
+    let mut s = [0; 64];
+    read_hex(path, &mut s)?;
+    Ok(s)
+}
+
+/// a new key pair, the secret seed in path and the public key in path.pub
+pub fn generate<P: AsRef<Path>>(path: P) -> io::Result<PublicKey> {
+    let seed = crypto::random_key()?;
+    let public = ed25519::public_key(&seed);
+    let mut pubpath = OsString::from(path.as_ref());
+    pubpath.push(".pub");
+    write_hex(&path, &seed, 0o600)?;
+    write_hex(&pubpath, &public, 0o644)?;
+    Ok(public)
+}
+
+/// write a detached signature of index
+pub fn sign_index<S: AsRef<Path>>(seed: &Seed, index: &Index, signature: S) -> io::Result<()> {
+    write_hex(signature, &sign(seed, &merkle::root(index)?)[..], 0o644)
+}
+
+pub fn verify_index<S: AsRef<Path>>(public: &PublicKey, index: &Index, signature: S) -> io::Result<()> {
+    verify(public, &merkle::root(index)?, &read_signature(signature)?)
+}
+
+
+#[test]
+fn signed_index() {
+    let tmp = ::tarball::TempDir::new("signature");
+    let key = tmp.join("key");
+    let public = generate(&key).unwrap();
+    assert_eq!(read_public(tmp.join("key.pub")).unwrap(), public);
+    let seed = read_seed(&key).unwrap();
+
+    let mut bs = ::blockstore::memory();
+    let mut index = ::tarball::from_tar(&::tarball::tar_fixture()[..], &mut bs, &tmp).unwrap();
+    let sig = signature_path(tmp.join("index"));
+    sign_index(&seed, &index, &sig).unwrap();
+    verify_index(&public, &index, &sig).unwrap();
+
+    // another key, or a changed index
+    let other = generate(tmp.join("other")).unwrap();
+    assert!(verify_index(&other, &index, &sig).is_err());
+    index.inodes[1].u = 1000;
+    assert_eq!(verify_index(&public, &index, &sig).unwrap_err().kind(), ErrorKind::InvalidData);
+}
@@ -0,0 +1,229 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Write, BufReader, BufWriter, ErrorKind};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use serde_json;
+
+use blockstore::BlockStore;
+use filter::Filter;
+use index::{self, Inode, ContentBlockEntry, ContentDirEntry};
+use serializer::{Chunker, Content};
+
+
+/// inodes which got a number but weren't written yet, in a file instead of memory
+struct Queue {
+    path:   PathBuf,
+    writer: BufWriter<File>,
+    reader: BufReader<File>,
+}
+
+impl Queue {
+    fn create(path: &Path) -> io::Result<Queue> {
+        let writer = File::create(path)?;
+        let reader = File::open(path)?;
+        Ok(Queue{
+            path:   path.to_path_buf(),
+            writer: BufWriter::new(writer),
+            reader: BufReader::new(reader),
+        })
+    }
+
+    fn push(&mut self, i: u64, parent: u64, path: &Path) -> io::Result<()> {
+        let b = path.as_os_str().as_bytes();
+        self.writer.write_all(&i.to_le_bytes())?;
+        self.writer.write_all(&parent.to_le_bytes())?;
+        self.writer.write_all(&(b.len() as u64).to_le_bytes())?;
+        self.writer.write_all(b)
+    }
+
+    fn number(&mut self) -> io::Result<u64> {
+        let mut n = [0; 8];
+        self.reader.read_exact(&mut n)?;
+        Ok(u64::from_le_bytes(n))
+    }
+
+    fn pop(&mut self) -> io::Result<Option<(u64, u64, PathBuf)>> {
+        self.writer.flush()?;
+        let i = match self.number() {
+            Ok(i) => i,
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let parent = self.number()?;
+        let mut path = vec![0; self.number()? as usize];
+        self.reader.read_exact(&mut path)?;
+        Ok(Some((i, parent, PathBuf::from(OsString::from_vec(path)))))
+    }
+}
+
+impl Drop for Queue {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+
+/// inodes waiting for the chunker to finish their content, first is the number of the front one
+struct Pending {
+    first:  u64,
+    inodes: VecDeque<Inode>,
+}
+
+impl Content for Pending {
+    fn host_path(&self, inode: u64) -> &OsString {
+        &self.inodes[(inode - self.first) as usize].host_path
+    }
+
+    fn add_block(&mut self, inode: u64, block: ContentBlockEntry) {
+        self.inodes[(inode - self.first) as usize].c.get_or_insert(Vec::new()).push(block);
+    }
+
+    fn set_hash(&mut self, inode: u64, hash: String) {
+        self.inodes[(inode - self.first) as usize].h = Some(hash);
+    }
+}
+
+
+/// writes the index of a host directory while walking and chunking it, so memory use
+/// doesn't grow with the size of the tree. inodes are numbered like index::from_host
+/// does, and the result is the same index file from_host and serialize would give.
+pub struct Builder<W: Write> {
+    out:     W,
+    queue:   Queue,
+    pending: Pending,
+    next:    u64, // number of the next new inode
+
+    // owner of every inode, instead of the one on the host
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl<W: Write> Builder<W> {
+    /// numbered inodes wait in a file at queue until they are written to out
+    pub fn new(out: W, queue: &Path) -> io::Result<Builder<W>> {
+        Ok(Builder{
+            out:     out,
+            queue:   Queue::create(queue)?,
+            pending: Pending{
+                first:  0,
+                inodes: VecDeque::new(),
+            },
+            next:    0,
+            uid:     None,
+            gid:     None,
+        })
+    }
+
+    /// walk host, returns the number of inodes written
+    pub fn build(mut self, host: &Path, filter: &Filter, blockstore: &mut BlockStore, mut chunker: Chunker) -> io::Result<u64> {
+        let dev = fs::metadata(host)?.dev();
+
+        self.out.write_all(b"{\"inodes\":[")?;
+        self.queue.push(0, 0, host)?;
+        self.next = 1;
+        while let Some((i, parent, path)) = self.queue.pop()? {
+            let mut inode = if i == 0 {
+                let mut root = index::host_inode(0, 0, &path, &fs::metadata(&path)?)?;
+                root.c = None;
+                root
+            } else {
+                index::host_inode(i, parent, &path, &fs::symlink_metadata(&path)?)?
+            };
+
+            if inode.k == 1 {
+                // keep a mount point, but not what's mounted on it
+                let mounted = i != 0 && filter.one_file_system && fs::symlink_metadata(&path)?.dev() != dev;
+                inode.d = Some(if mounted { BTreeMap::new() } else { self.list(i, host, &path, filter)? });
+            }
+
+            let kind = inode.k;
+            self.pending.inodes.push_back(inode);
+            if kind == 2 {
+                let file = BufReader::new(File::open(&path)?);
+                chunker.add(&mut self.pending, blockstore, i, file)?;
+            }
+            let unfinished = chunker.unfinished();
+            self.flush(unfinished)?;
+        }
+        chunker.finish(&mut self.pending, blockstore)?;
+        self.flush(None)?;
+
+        self.out.write_all(b"]}")?;
+        self.out.flush()?;
+        Ok(self.next)
+    }
+
+    /// give the entries of a directory their numbers
+    fn list(&mut self, dir: u64, host: &Path, path: &Path, filter: &Filter) -> io::Result<BTreeMap<OsString, ContentDirEntry>> {
+        let rel = path.strip_prefix(host).unwrap().as_os_str().as_bytes();
+        let mut d = BTreeMap::new();
+        for e in index::host_entries(path, rel, filter)? {
+            d.insert(e.file_name(), ContentDirEntry{
+                i: self.next,
+                k: index::kind(&e.metadata()?),
+            });
+            self.queue.push(self.next, dir, &e.path())?;
+            self.next += 1;
+        }
+        Ok(d)
+    }
+
+    /// write pending inodes before unfinished, or all of them
+    fn flush(&mut self, unfinished: Option<u64>) -> io::Result<()> {
+        while self.pending.inodes.front().map_or(false, |i| unfinished.map_or(true, |u| i.i < u)) {
+            let mut inode = self.pending.inodes.pop_front().unwrap();
+            self.pending.first += 1;
+            if let Some(uid) = self.uid {
+                inode.u = uid;
+            }
+            if let Some(gid) = self.gid {
+                inode.g = gid;
+            }
+            if inode.i != 0 {
+                self.out.write_all(b",")?;
+            }
+            serde_json::to_writer(&mut self.out, &inode)?;
+        }
+        Ok(())
+    }
+}
+
+
+#[test]
+fn streamed_index() {
+    let tmp = ::tarball::TempDir::new("stream");
+
+    let mut filter = Filter::new();
+    filter.exclude("*.jpg");
+    filter.exclude("/rust_*/");
+    filter.exclude("/samefile/");
+    let mut bs = ::blockstore::memory();
+    let mut expected = index::from_host_filtered(OsString::from("test"), &filter);
+    let mut chunker = Chunker::new();
+    chunker.secret = Some([3; 32]);
+    expected.serialize_with(&mut bs, chunker).unwrap();
+    expected.set_owner(Some(0), None);
+
+    let mut out = Vec::new();
+    let mut streamed = ::blockstore::memory();
+    let n = {
+        let mut b = Builder::new(&mut out, &tmp.join("queue")).unwrap();
+        b.uid = Some(0);
+        let mut chunker = Chunker::new();
+        chunker.secret = Some([3; 32]);
+        b.build(Path::new("test"), &filter, &mut streamed, chunker).unwrap()
+    };
+    assert!(!tmp.join("queue").exists());
+
+    assert_eq!(n, expected.inodes.len() as u64);
+    assert!(out == serde_json::to_vec(&expected).unwrap());
+    let mut blocks = streamed.list();
+    let mut expected_blocks = bs.list();
+    blocks.sort();
+    expected_blocks.sort();
+    assert_eq!(blocks, expected_blocks);
+    index::from_bytes(&out, None).unwrap();
+}
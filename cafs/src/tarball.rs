@@ -0,0 +1,330 @@
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read, BufRead, BufReader, ErrorKind};
+use std::path::{Path, Component};
+use flate2;
+use tar;
+use zstd;
+
+use index::{Index, Inode, ContentDirEntry};
+use blockstore::BlockStore;
+use serializer::Chunker;
+
+
+/// detect gzip and zstd compressed streams by their magic, anything else is passed through as is
+pub fn decompress<'a, R: Read + 'a>(r: R) -> io::Result<Box<Read + 'a>> {
+    let mut r = BufReader::new(r);
+    let magic = r.fill_buf()?.to_vec();
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(flate2::read::MultiGzDecoder::new(r)))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Ok(Box::new(zstd::Decoder::with_buffer(r)?))
+    } else {
+        Ok(Box::new(r))
+    }
+}
+
+/// path components inside the archive, without leading "./" or "/"
+fn components(path: &Path) -> io::Result<Vec<OsString>> {
+    let mut r = Vec::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(n) => r.push(n.to_os_string()),
+            Component::CurDir | Component::RootDir => {},
+            _ => {
+                return Err(io::Error::new(ErrorKind::InvalidData,
+                                          format!("invalid path in archive: {:?}", path)));
+            },
+        }
+    }
+    Ok(r)
+}
+
+/// components joined for messages
+fn display(path: &[OsString]) -> String {
+    path.iter().map(|n| n.to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+pub fn new_inode(index: &mut Index, parent: u64, name: OsString, kind: u16, mode: u32, uid: u32, gid: u32) -> u64 {
+    let i = index.inodes.len() as u64;
+    index.inodes.push(Inode{
+        i: i,
+        p: parent,
+        s: 0,
+        k: kind,
+        a: (mode & 0o7777) as u16,
+        u: uid,
+        g: gid,
+
+        d: match kind {
+            1 => Some(BTreeMap::new()),
+            _ => None,
+        },
+        h: None,
+        c: Some(Vec::new()),
+        l: None,
+
+        host_path: OsString::new(),
+    });
+    index.inodes[parent as usize].d.get_or_insert(BTreeMap::new()).insert(name, ContentDirEntry{
+        i: i,
+        k: kind,
+    });
+    i
+}
+
+fn child(index: &Index, parent: u64, name: &OsStr) -> Option<u64> {
+    index.inodes[parent as usize].d.as_ref().and_then(|d| d.get(name)).map(|e| e.i)
+}
+
+/// find the directory for path, creating missing ones. tar archives don't need to contain
+/// entries for every parent directory.
+fn mkdir_all(index: &mut Index, path: &[OsString]) -> io::Result<u64> {
+    let mut cur = 0;
+    for name in path {
+        cur = match child(index, cur, name) {
+            Some(i) => {
+                if index.inodes[i as usize].k != 1 {
+                    return Err(io::Error::new(ErrorKind::InvalidData,
+                                              format!("{} in archive is not a directory", display(&path))));
+                }
+                i
+            },
+            None => new_inode(index, cur, name.clone(), 1, 0o755, 0, 0),
+        };
+    }
+    Ok(cur)
+}
+
+/// build an index from a tar stream. since the stream can't be reopened later,
+/// block content is stored as loose files in blockdir.
+pub fn from_tar<R: Read>(r: R, blockstore: &mut BlockStore, blockdir: &Path) -> io::Result<Index> {
+    from_tar_with(r, blockstore, Chunker::loose(blockdir))
+}
+
+pub fn from_tar_with<R: Read>(r: R, blockstore: &mut BlockStore, mut chunker: Chunker) -> io::Result<Index> {
+    let mut index = Index{
+        inodes:  Vec::new(),
+    };
+    index.inodes.push(Inode{
+        i: 0,
+        p: 0,
+        s: 0,
+        k: 1,
+        a: 0o755,
+        u: 0,
+        g: 0,
+
+        d: Some(BTreeMap::new()),
+        h: None,
+        c: None,
+        l: None,
+
+        host_path: OsString::new(),
+    });
+
+    let mut archive = tar::Archive::new(r);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = components(&entry.path()?)?;
+
+        let (mode, uid, gid, kind) = {
+            let h = entry.header();
+            (h.mode()?, h.uid()? as u32, h.gid()? as u32, h.entry_type())
+        };
+
+        // the archive root itself
+        if path.len() < 1 {
+            if kind.is_dir() {
+                index.inodes[0].a = (mode & 0o7777) as u16;
+                index.inodes[0].u = uid;
+                index.inodes[0].g = gid;
+            }
+            continue;
+        }
+
+        let (name, dir) = path.split_last().unwrap();
+        let parent = mkdir_all(&mut index, dir)?;
+
+        if kind.is_dir() {
+            match child(&index, parent, name) {
+                Some(i) if index.inodes[i as usize].k == 1 => {
+                    let ref mut inode = index.inodes[i as usize];
+                    inode.a = (mode & 0o7777) as u16;
+                    inode.u = uid;
+                    inode.g = gid;
+                },
+                _ => {
+                    new_inode(&mut index, parent, name.clone(), 1, mode, uid, gid);
+                },
+            }
+        } else if kind.is_file() || kind.is_contiguous() || kind.is_gnu_sparse() {
+            let i = new_inode(&mut index, parent, name.clone(), 2, mode, uid, gid);
+            index.inodes[i as usize].s = entry.size();
+            chunker.add(&mut index, blockstore, i, &mut entry)?;
+        } else if kind.is_symlink() {
+            let target = match entry.link_name()? {
+                Some(t) => t.into_owned().into_os_string(),
+                None => {
+                    return Err(io::Error::new(ErrorKind::InvalidData,
+                                              format!("symlink {} without target", display(&path))));
+                },
+            };
+            let i = new_inode(&mut index, parent, name.clone(), 3, mode, uid, gid);
+            index.inodes[i as usize].s = target.len() as u64;
+            index.inodes[i as usize].l = Some(target);
+        } else if kind.is_hard_link() {
+            let target = match entry.link_name()? {
+                Some(t) => components(&t)?,
+                None => Vec::new(),
+            };
+            let mut cur = Some(0);
+            for n in &target {
+                cur = cur.and_then(|c| child(&index, c, n));
+            }
+            let i = match cur {
+                Some(i) if target.len() > 0 => i,
+                _ => {
+                    return Err(io::Error::new(ErrorKind::InvalidData,
+                                              format!("hardlink {} to unknown target {}", display(&path), display(&target))));
+                },
+            };
+            let k = index.inodes[i as usize].k;
+            index.inodes[parent as usize].d.get_or_insert(BTreeMap::new()).insert(name.clone(), ContentDirEntry{
+                i: i,
+                k: k,
+            });
+        } else {
+            warn!("skipping unsupported entry {} ({:?})", display(&path), kind);
+        }
+    }
+
+    chunker.finish(&mut index, blockstore)?;
+    Ok(index)
+}
+
+
+#[cfg(test)]
+pub fn tar_fixture() -> Vec<u8> {
+    let mut b = tar::Builder::new(Vec::new());
+
+    let mut h = tar::Header::new_gnu();
+    h.set_entry_type(tar::EntryType::Directory);
+    h.set_mode(0o700);
+    h.set_uid(12);
+    h.set_gid(34);
+    h.set_size(0);
+    h.set_cksum();
+    b.append_data(&mut h, "./etc/", io::empty()).unwrap();
+
+    let content = b"nameserver 127.0.0.1\n";
+    let mut h = tar::Header::new_gnu();
+    h.set_mode(0o640);
+    h.set_uid(0);
+    h.set_gid(5);
+    h.set_size(content.len() as u64);
+    h.set_cksum();
+    b.append_data(&mut h, "./etc/resolv.conf", &content[..]).unwrap();
+
+    let mut h = tar::Header::new_gnu();
+    h.set_entry_type(tar::EntryType::Link);
+    h.set_mode(0o640);
+    h.set_uid(0);
+    h.set_gid(5);
+    h.set_size(0);
+    h.set_cksum();
+    b.append_link(&mut h, "./usr/share/resolv.conf", "./etc/resolv.conf").unwrap();
+
+    let mut h = tar::Header::new_gnu();
+    h.set_entry_type(tar::EntryType::Symlink);
+    h.set_mode(0o777);
+    h.set_uid(0);
+    h.set_gid(0);
+    h.set_size(0);
+    h.set_cksum();
+    b.append_link(&mut h, "./resolv.conf", "etc/resolv.conf").unwrap();
+
+    b.into_inner().unwrap()
+}
+
+/// a directory for a test, removed with everything in it when dropped
+#[cfg(test)]
+pub struct TempDir(pub ::std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = ::std::env::temp_dir().join(format!("cafs-test-{}-{}", name, ::std::process::id()));
+        let _ = ::std::fs::remove_dir_all(&path);
+        ::std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+}
+
+#[cfg(test)]
+impl ::std::ops::Deref for TempDir {
+    type Target = Path;
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = ::std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// the index of tar_fixture, its blocks are written to a temp dir that lives as long as it's kept
+#[cfg(test)]
+pub fn fixture(name: &str, blockstore: &mut BlockStore) -> (TempDir, Index) {
+    let tmp = TempDir::new(name);
+    let index = from_tar(&tar_fixture()[..], blockstore, &tmp).unwrap();
+    (tmp, index)
+}
+
+#[test]
+fn tar_to_index() {
+    use std::io::Write;
+
+    let blockdir = TempDir::new("tar");
+
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(&tar_fixture()).unwrap();
+    let gz = gz.finish().unwrap();
+
+    let mut bs = ::blockstore::memory();
+    let index = from_tar(decompress(&gz[..]).unwrap(), &mut bs, &blockdir).unwrap();
+
+    let root = index.inodes[0].d.as_ref().unwrap();
+    let etc  = &index.inodes[root[OsStr::new("etc")].i as usize];
+    assert_eq!(etc.k, 1);
+    assert_eq!(etc.a, 0o700);
+    assert_eq!((etc.u, etc.g), (12, 34));
+
+    let conf = &index.inodes[etc.d.as_ref().unwrap()[OsStr::new("resolv.conf")].i as usize];
+    assert_eq!(conf.k, 2);
+    assert_eq!(conf.a, 0o640);
+    assert_eq!(conf.g, 5);
+
+    let usr   = &index.inodes[root[OsStr::new("usr")].i as usize];
+    let share = &index.inodes[usr.d.as_ref().unwrap()[OsStr::new("share")].i as usize];
+    assert_eq!(share.d.as_ref().unwrap()[OsStr::new("resolv.conf")].i, conf.i);
+
+    let link = &index.inodes[root[OsStr::new("resolv.conf")].i as usize];
+    assert_eq!(link.k, 3);
+    assert_eq!(link.l, Some(OsString::from("etc/resolv.conf")));
+
+    let mut content = String::new();
+    conf.chain(&bs).read_to_string(&mut content).unwrap();
+    assert_eq!(content, "nameserver 127.0.0.1\n");
+}
@@ -0,0 +1,75 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+
+pub const DEFAULT_THREADS: usize = 4;
+
+type Job<'a> = Box<FnOnce() + Send + 'a>;
+
+/// hands jobs to worker threads. without threads, jobs run right away on the calling thread.
+pub struct Pool<'a> {
+    jobs: Option<Sender<Job<'a>>>,
+}
+
+impl<'a> Pool<'a> {
+    pub fn execute<F: FnOnce() + Send + 'a>(&self, f: F) {
+        match self.jobs {
+            Some(ref jobs) => jobs.send(Box::new(f)).expect("worker threads are gone"),
+            None => f(),
+        }
+    }
+}
+
+/// run f with a pool of threads, whose jobs may borrow anything that outlives the call.
+/// returns after f returned and the pool it was given has finished all its jobs.
+pub fn scope<'a, F, R>(threads: usize, f: F) -> R where F: FnOnce(Pool<'a>) -> R {
+    if threads < 1 {
+        return f(Pool{jobs: None});
+    }
+    let (tx, rx) = channel::<Job<'a>>();
+    let rx = Mutex::new(rx);
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| loop {
+                let job = rx.lock().unwrap().recv();
+                match job {
+                    // a panicking job must not take its worker with it
+                    Ok(job) => { let _ = panic::catch_unwind(AssertUnwindSafe(job)); },
+                    Err(_) => break,
+                }
+            });
+        }
+        f(Pool{jobs: Some(tx)})
+    })
+}
+
+
+#[test]
+fn borrowing_jobs() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let done = AtomicUsize::new(0);
+    let threads = Mutex::new(Vec::new());
+    let r = scope(3, |pool| {
+        let (done, threads) = (&done, &threads);
+        for n in 0..100 {
+            pool.execute(move || {
+                threads.lock().unwrap().push(thread::current().id());
+                done.fetch_add(n, Ordering::SeqCst);
+            });
+        }
+        pool.execute(|| panic!("one bad job"));
+        pool.execute(move || { done.fetch_add(1, Ordering::SeqCst); });
+        "ok"
+    });
+    assert_eq!(r, "ok");
+    assert_eq!(done.load(Ordering::SeqCst), 4951);
+    assert!(threads.lock().unwrap().iter().all(|&t| t != thread::current().id()));
+
+    // no threads, no queue
+    let mut v = Vec::new();
+    scope(0, |pool| pool.execute(|| v.push(1)));
+    assert_eq!(v, vec![1]);
+}